@@ -0,0 +1,171 @@
+use std::{fs::Permissions, os::unix::fs::PermissionsExt, path::{Path, PathBuf}};
+
+use anyhow::{bail, Context};
+use chrono::Datelike;
+use clap::Parser;
+use rcgen::{date_time_ymd, BasicConstraints, CertificateParams, DistinguishedName, DnType, ExtendedKeyUsagePurpose, IsCa, Issuer, KeyPair, KeyUsagePurpose};
+use tokio::io::AsyncWriteExt;
+
+const CA_CERT: &str = "ca.crt";
+const CA_KEY: &str = "ca.key";
+const CA_TRUST_NAME: &str = "zedmirs-ca.crt";
+
+#[derive(Clone, Parser)]
+pub struct CertsOpts {
+    #[arg(long, default_value="api.zed.dev,zed.dev", value_delimiter=',',
+        help="DNS names and IP addresses the certificate is valid for, comma separated")]
+    pub san: Vec<String>,
+    #[arg(long, help="Directory the PEM files are written to [default: OUTPUT/certs]")]
+    pub dir: Option<String>,
+    #[arg(long, default_value_t=825u32, help="Days the certificate is valid")]
+    pub days: u32,
+    #[arg(long, default_value_t=3650u32, help="Days a newly created CA is valid")]
+    pub ca_days: u32,
+    #[arg(long, help="Create a new CA even if one exists, which then has to be trusted again")]
+    pub new_ca: bool,
+}
+
+/// Creates a local CA, or reuses the one from an earlier run, and signs a server certificate
+/// for the configured names with it.
+pub async fn certs(opts: &CertsOpts, output: &str) -> anyhow::Result<()> {
+    let Some(name) = opts.san.first() else {
+        bail!("at least one --san is required")
+    };
+
+    let dir = PathBuf::from(opts.dir.clone().unwrap_or_else(|| format!("{}/certs", output.trim_end_matches('/'))));
+
+    tokio::fs::create_dir_all(&dir).await?;
+
+    let ca_cert_path = dir.join(CA_CERT);
+    let ca_key_path = dir.join(CA_KEY);
+
+    let reuse_ca = !opts.new_ca && tokio::fs::try_exists(&ca_cert_path).await? && tokio::fs::try_exists(&ca_key_path).await?;
+
+    let (ca_pem, issuer) = if reuse_ca {
+        let ca_pem = tokio::fs::read_to_string(&ca_cert_path).await
+            .with_context(|| format!("reading {}", ca_cert_path.display()))?;
+
+        let ca_key = KeyPair::from_pem(&tokio::fs::read_to_string(&ca_key_path).await?)
+            .with_context(|| format!("parsing {}", ca_key_path.display()))?;
+
+        let issuer = Issuer::from_ca_cert_pem(&ca_pem, ca_key)
+            .with_context(|| format!("parsing {}", ca_cert_path.display()))?;
+
+        crate::log(format!("Signing with the existing CA {}", ca_cert_path.display()));
+
+        (ca_pem, issuer)
+    } else {
+        let ca_key = KeyPair::generate()?;
+        let ca_params = ca_params(opts.ca_days);
+
+        let ca_pem = ca_params.self_signed(&ca_key)?.pem();
+
+        write_pem(&ca_key_path, &ca_key.serialize_pem(), true).await?;
+        write_pem(&ca_cert_path, &ca_pem, false).await?;
+
+        crate::log(format!("Created CA {}", ca_cert_path.display()));
+
+        (ca_pem, Issuer::new(ca_params, ca_key))
+    };
+
+    let key = KeyPair::generate()?;
+
+    let cert_pem = server_params(&opts.san, opts.days)?
+        .signed_by(&key, &issuer)?
+        .pem();
+
+    let cert_path = dir.join(format!("{name}.crt"));
+    let key_path = dir.join(format!("{name}.key"));
+
+    write_pem(&key_path, &key.serialize_pem(), true).await?;
+    // The chain includes the CA, for clients that are given the CA some other way than the trust store.
+    write_pem(&cert_path, &format!("{cert_pem}{ca_pem}"), false).await?;
+
+    crate::log(format!("Created certificate {} for {}", cert_path.display(), opts.san.join(", ")));
+
+    print_instructions(&ca_cert_path, &cert_path, &key_path, &opts.san);
+
+    Ok(())
+}
+
+fn ca_params(days: u32) -> CertificateParams {
+    let mut params = CertificateParams::default();
+
+    let mut distinguished_name = DistinguishedName::new();
+    distinguished_name.push(DnType::CommonName, "zedmirs local CA");
+    distinguished_name.push(DnType::OrganizationName, "zedmirs");
+
+    params.distinguished_name = distinguished_name;
+    params.is_ca = IsCa::Ca(BasicConstraints::Constrained(0));
+    params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign, KeyUsagePurpose::DigitalSignature];
+
+    set_validity(&mut params, days);
+
+    params
+}
+
+fn server_params(san: &[String], days: u32) -> anyhow::Result<CertificateParams> {
+    let mut params = CertificateParams::new(san.to_vec())
+        .with_context(|| "invalid --san")?;
+
+    let mut distinguished_name = DistinguishedName::new();
+    distinguished_name.push(DnType::CommonName, san[0].as_str());
+
+    params.distinguished_name = distinguished_name;
+    params.is_ca = IsCa::ExplicitNoCa;
+    params.key_usages = vec![KeyUsagePurpose::DigitalSignature];
+    params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+    params.use_authority_key_identifier_extension = true;
+
+    set_validity(&mut params, days);
+
+    Ok(params)
+}
+
+/// Valid from yesterday, so that clocks running slightly behind accept the certificate.
+fn set_validity(params: &mut CertificateParams, days: u32) {
+    let today = chrono::Utc::now().date_naive();
+
+    let date = |d: chrono::NaiveDate| date_time_ymd(d.year(), d.month() as u8, d.day() as u8);
+
+    params.not_before = date(today - chrono::Days::new(1));
+    params.not_after = date(today + chrono::Days::new(days as u64));
+}
+
+async fn write_pem(path: &Path, pem: &str, private: bool) -> anyhow::Result<()> {
+    let mode = if private { 0o600 } else { 0o644 };
+
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .mode(mode)
+        .open(path).await
+        .with_context(|| format!("writing {}", path.display()))?;
+
+    // The mode above only applies to new files and is narrowed by the umask. Set it on existing
+    // files too, while they are still empty.
+    file.set_permissions(Permissions::from_mode(mode)).await
+        .with_context(|| format!("setting permissions of {}", path.display()))?;
+
+    file.write_all(pem.as_bytes()).await?;
+    file.sync_all().await?;
+
+    Ok(())
+}
+
+fn print_instructions(ca_cert_path: &Path, cert_path: &Path, key_path: &Path, san: &[String]) {
+    let ca = ca_cert_path.display();
+
+    println!();
+    println!("Serve with the certificate:");
+    println!("  zedmirs --output OUTPUT serve --tls-cert {} --tls-key {} --tls-port 443", cert_path.display(), key_path.display());
+    println!("or point ssl_certificate and ssl_certificate_key of nginx at them.");
+    println!();
+    println!("Trust the CA on every machine running Zed:");
+    println!("  Debian, Ubuntu:       sudo cp {ca} /usr/local/share/ca-certificates/{CA_TRUST_NAME} && sudo update-ca-certificates");
+    println!("  Fedora, RHEL:         sudo cp {ca} /etc/pki/ca-trust/source/anchors/{CA_TRUST_NAME} && sudo update-ca-trust");
+    println!("  Arch, openSUSE:       sudo trust anchor --store {ca}");
+    println!();
+    println!("Then resolve {} to the mirror, e.g. in /etc/hosts, and restart Zed.", san.join(" and "));
+}
@@ -1,6 +1,6 @@
 use clap::{Parser, Subcommand};
 
-use crate::{mirror::{mirror, MirrorOpts}, serve::{serve, ServeOpts}};
+use crate::{certs::{certs, CertsOpts}, daemon::{daemon, DaemonOpts}, gc::{gc, GcOpts}, generation::{rollback, RollbackOpts}, mirror::{mirror, MirrorOpts}, reindex::{reindex, ReindexOpts}, serve::{serve, ServeCommandOpts}, verify::{verify, VerifyOpts}};
 
 
 #[derive(Parser)]
@@ -16,7 +16,13 @@ pub struct Config {
 #[derive(Subcommand)]
 pub enum Op {
     Mirror(MirrorOpts),
-    Serve(ServeOpts)
+    Serve(ServeCommandOpts),
+    Verify(VerifyOpts),
+    Gc(GcOpts),
+    Rollback(RollbackOpts),
+    Daemon(DaemonOpts),
+    Certs(CertsOpts),
+    Reindex(ReindexOpts)
 }
 
 impl Op {
@@ -24,6 +30,12 @@ impl Op {
         match self {
             Op::Mirror(opts) => mirror(opts, &config.output).await,
             Op::Serve(opts) => serve(opts, &config.output).await,
+            Op::Verify(opts) => verify(opts, &config.output).await,
+            Op::Gc(opts) => gc(opts, &config.output).await,
+            Op::Rollback(opts) => rollback(opts, &config.output).await,
+            Op::Daemon(opts) => daemon(opts, &config.output).await,
+            Op::Certs(opts) => certs(opts, &config.output).await,
+            Op::Reindex(opts) => reindex(opts, &config.output).await,
         }
     }
 }
\ No newline at end of file
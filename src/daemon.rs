@@ -0,0 +1,141 @@
+use std::{sync::{Arc, Mutex}, time::Duration};
+
+use axum::{extract::State, routing::get, Json, Router};
+use clap::Parser;
+use serde::Serialize;
+use tokio::time::MissedTickBehavior;
+
+use crate::{generation::{current_generation, current_path}, mirror::{mirror, MirrorOpts}, serve::{listen, router, AppState, ServeOpts}};
+
+#[derive(Clone, Parser)]
+pub struct DaemonOpts {
+    #[arg(long, default_value_t=3600u64,
+        help="Seconds between the starts of two mirror runs")]
+    pub mirror_interval: u64,
+    #[command(flatten)]
+    pub serve: ServeOpts,
+    #[command(flatten)]
+    pub mirror: MirrorOpts,
+}
+
+/// The state of the scheduled mirror runs, served at `/status`.
+#[derive(Clone, Default, Serialize)]
+pub struct MirrorStatus {
+    pub running: bool,
+    pub runs: u64,
+    pub skipped_runs: u64,
+    pub last_started_at: Option<String>,
+    pub last_finished_at: Option<String>,
+    pub last_success: Option<bool>,
+    pub last_error: Option<String>,
+    pub generation: Option<String>,
+}
+
+type SharedStatus = Arc<Mutex<MirrorStatus>>;
+
+/// Serves the mirror while mirroring it again every `--mirror-interval` seconds.
+pub async fn daemon(opts: &DaemonOpts, mut output: &str) -> anyhow::Result<()> {
+    if let Some(path) = output.strip_suffix('/') {
+        output = path
+    }
+
+    let status = SharedStatus::default();
+    status.lock().expect("status lock poisoned").generation = current_generation(output).await;
+
+    let has_index = tokio::fs::try_exists(current_path(output).join("idx")).await?;
+
+    // There is nothing to serve yet, so the first run has to finish before the server starts.
+    if !has_index {
+        crate::log("No index found, mirroring before serving");
+
+        start_run(&status);
+        let res = mirror(&opts.mirror, output).await;
+        finish_run(&status, output, &res).await;
+        res?;
+    }
+
+    let state = AppState::init(output, &opts.serve, &opts.mirror.downloader).await?;
+
+    spawn_scheduler(opts.clone(), output.to_string(), state.clone(), status.clone(), has_index);
+
+    let app = router(state.clone())
+        .merge(Router::new()
+            .route("/status", get(get_status))
+            .with_state(status));
+
+    listen(&opts.serve, state, app).await
+}
+
+fn spawn_scheduler(opts: DaemonOpts, output: String, state: AppState, status: SharedStatus, run_now: bool) {
+    tokio::spawn(async move {
+        let period = Duration::from_secs(opts.mirror_interval.max(1));
+
+        let start = if run_now {
+            tokio::time::Instant::now()
+        } else {
+            tokio::time::Instant::now() + period
+        };
+
+        let mut ticker = tokio::time::interval_at(start, period);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            ticker.tick().await;
+
+            if !start_run(&status) {
+                crate::log("WARN skipping scheduled mirror run, the previous one is still going");
+                status.lock().expect("status lock poisoned").skipped_runs += 1;
+                continue
+            }
+
+            let opts = opts.clone();
+            let output = output.clone();
+            let state = state.clone();
+            let status = status.clone();
+
+            tokio::spawn(async move {
+                let res = mirror(&opts.mirror, &output).await;
+
+                match &res {
+                    Ok(()) => if let Err(e) = state.reload(false).await {
+                        crate::log(format!("WARN failed to reload index: {e:#}"));
+                    },
+                    Err(e) => crate::log(format!("WARN mirror run failed: {e:#}"))
+                }
+
+                finish_run(&status, &output, &res).await;
+            });
+        }
+    });
+}
+
+/// Marks a run as started, unless one is going already.
+fn start_run(status: &SharedStatus) -> bool {
+    let mut status = status.lock().expect("status lock poisoned");
+
+    if status.running {
+        return false
+    }
+
+    status.running = true;
+    status.runs += 1;
+    status.last_started_at = Some(crate::now());
+
+    true
+}
+
+async fn finish_run(status: &SharedStatus, output: &str, res: &anyhow::Result<()>) {
+    let generation = current_generation(output).await;
+
+    let mut status = status.lock().expect("status lock poisoned");
+
+    status.running = false;
+    status.last_finished_at = Some(crate::now());
+    status.last_success = Some(res.is_ok());
+    status.last_error = res.as_ref().err().map(|e| format!("{e:#}"));
+    status.generation = generation;
+}
+
+async fn get_status(State(status): State<SharedStatus>) -> Json<MirrorStatus> {
+    Json(status.lock().expect("status lock poisoned").clone())
+}
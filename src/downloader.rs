@@ -1,18 +1,116 @@
 
-use std::{path::{Path, PathBuf}, sync::Arc};
+use std::{fmt::Display, path::{Path, PathBuf}, str::FromStr, sync::{Arc, Mutex}, time::Duration};
 
 use anyhow::{bail, Context};
 use async_channel::{bounded, Sender, Receiver};
-use reqwest::{Client, StatusCode};
-use tokio::{fs::{remove_file, symlink}, io::AsyncWriteExt, task::JoinHandle};
+use clap::Parser;
+use reqwest::{header::{HeaderMap, HeaderName, HeaderValue, ACCEPT_ENCODING, CONTENT_RANGE, ETAG, IF_RANGE, LAST_MODIFIED, RANGE, RETRY_AFTER}, Certificate, Client, Proxy, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::{fs::{remove_file, symlink}, io::{AsyncReadExt, AsyncWriteExt}, sync::oneshot, task::JoinHandle, time::sleep};
+
+use crate::{manifest::{ArchiveId, Manifest, ManifestEntry}, rate_limit::{parse_byte_rate, RateLimiter, Throttle}};
 
 use super::progress::Progress;
 
+const PART_SUFFIX: &str = ".part";
+const PART_STATE_SUFFIX: &str = ".part.json";
+
+#[derive(Clone, Parser)]
+pub struct DownloaderOpts {
+    #[arg(long, default_value_t=3u32,
+        help="Number of times a download is retried after a transient error")]
+    pub retries: u32,
+    #[arg(long, default_value_t=500u64,
+        help="Initial retry backoff in milliseconds, doubled for every attempt")]
+    pub retry_backoff_ms: u64,
+    #[arg(long, default_value_t=30_000u64,
+        help="Upper bound of the retry backoff in milliseconds")]
+    pub retry_max_backoff_ms: u64,
+    #[arg(long, help="Proxy url for all upstream requests, e.g. http://proxy.example.com:3128")]
+    pub proxy: Option<String>,
+    #[arg(long, requires="proxy", help="Proxy username")]
+    pub proxy_user: Option<String>,
+    #[arg(long, requires="proxy_user", help="Proxy password")]
+    pub proxy_password: Option<String>,
+    #[arg(long, help="Additional trusted root certificates (PEM bundle), can be repeated")]
+    pub ca_cert: Vec<String>,
+    #[arg(long, help="Connect timeout in seconds")]
+    pub connect_timeout: Option<u64>,
+    #[arg(long, help="Timeout in seconds for each read from the connection")]
+    pub read_timeout: Option<u64>,
+    #[arg(long, help="Timeout in seconds for a whole request, including the body")]
+    pub timeout: Option<u64>,
+    #[arg(long, default_value=concat!("zedmirs/", env!("CARGO_PKG_VERSION")),
+        help="User-Agent sent to the upstream")]
+    pub user_agent: String,
+    #[arg(long, value_parser=parse_header,
+        help="Extra request header as \"Name: value\", can be repeated")]
+    pub header: Vec<(HeaderName, HeaderValue)>,
+    #[arg(long, value_parser=parse_byte_rate,
+        help="Bandwidth limit shared by all downloads, e.g. 500K or 2M (bytes per second)")]
+    pub limit_rate: Option<u64>,
+    #[arg(long, value_parser=parse_byte_rate,
+        help="Bandwidth limit for each connection, e.g. 500K or 2M (bytes per second)")]
+    pub limit_rate_per_connection: Option<u64>,
+}
+
+impl DownloaderOpts {
+    pub fn build_http_client(&self) -> anyhow::Result<Client> {
+        let mut builder = Client::builder()
+            .user_agent(&self.user_agent)
+            .default_headers(self.header.iter().cloned().collect());
+
+        if let Some(proxy_url) = &self.proxy {
+            let mut proxy = Proxy::all(proxy_url)
+                .with_context(|| format!("invalid proxy url {proxy_url}"))?;
+
+            if let Some(user) = &self.proxy_user {
+                proxy = proxy.basic_auth(user, self.proxy_password.as_deref().unwrap_or_default());
+            }
+
+            builder = builder.proxy(proxy);
+        }
+
+        for ca_cert in &self.ca_cert {
+            let pem = std::fs::read(ca_cert)
+                .with_context(|| format!("reading {ca_cert}"))?;
+
+            for cert in Certificate::from_pem_bundle(&pem).with_context(|| format!("parsing {ca_cert}"))? {
+                builder = builder.add_root_certificate(cert);
+            }
+        }
+
+        if let Some(secs) = self.connect_timeout {
+            builder = builder.connect_timeout(Duration::from_secs(secs));
+        }
+
+        if let Some(secs) = self.read_timeout {
+            builder = builder.read_timeout(Duration::from_secs(secs));
+        }
+
+        if let Some(secs) = self.timeout {
+            builder = builder.timeout(Duration::from_secs(secs));
+        }
+
+        Ok(builder.build()?)
+    }
+}
+
+fn parse_header(s: &str) -> anyhow::Result<(HeaderName, HeaderValue)> {
+    let Some((name, value)) = s.split_once(':') else {
+        bail!("expected \"Name: value\"")
+    };
+
+    Ok((HeaderName::from_str(name.trim())?, HeaderValue::from_str(value.trim())?))
+}
+
 #[derive(Clone)]
 pub struct Downloader {
     sender: Sender<Box<Download>>,
     _tasks: Arc<Vec<JoinHandle<()>>>,
-    progress: Progress
+    progress: Progress,
+    failures: Arc<Mutex<Vec<FailedDownload>>>,
 }
 
 impl Default for Downloader {
@@ -21,38 +119,67 @@ impl Default for Downloader {
         Self {
             sender,
             _tasks: Default::default(),
-            progress: Default::default()
+            progress: Default::default(),
+            failures: Default::default(),
         }
     }
 }
 
+#[derive(Clone)]
+struct WorkerCtx {
+    http_client: Client,
+    progress: Progress,
+    manifest: Manifest,
+    retry: RetryPolicy,
+    throttle: Throttle,
+    failures: Arc<Mutex<Vec<FailedDownload>>>,
+}
+
 impl Downloader {
-    pub fn build(num_threads: u8) -> Self {
+    pub fn build(num_threads: u8, opts: &DownloaderOpts, manifest: Manifest) -> anyhow::Result<Self> {
         let (sender, receiver) = bounded(1024);
 
         let mut tasks = Vec::with_capacity(num_threads as usize);
         let progress = Progress::new();
-        let http_client = reqwest::Client::new();
+        let failures: Arc<Mutex<Vec<FailedDownload>>> = Default::default();
+
+        let ctx = WorkerCtx {
+            http_client: opts.build_http_client()
+                .with_context(|| "building http client")?,
+            progress: progress.clone(),
+            manifest,
+            retry: RetryPolicy::from(opts),
+            throttle: Throttle {
+                global: opts.limit_rate.map(RateLimiter::new),
+                connection: None,
+            },
+            failures: failures.clone(),
+        };
+
+        progress.set_rate_limit(opts.limit_rate);
 
         for _ in 0..num_threads {
             let task_receiver: Receiver<Box<Download>> = receiver.clone();
-            let task_progress = progress.clone();
-            let task_http_client = http_client.clone();
+            let mut task_ctx = ctx.clone();
+
+            // Every task handles one download at a time, so a limiter per task limits each connection.
+            task_ctx.throttle.connection = opts.limit_rate_per_connection.map(RateLimiter::new);
 
             let handle = tokio::spawn(async move {
                 while let Ok(dl) = task_receiver.recv().await {
-                    _ = Downloader::download_and_track(&task_http_client, task_progress.clone(), dl).await;
+                    Downloader::download_and_track(&task_ctx, dl).await;
                 }
             });
 
             tasks.push(handle);
         }
 
-        Self {
+        Ok(Self {
             sender,
             _tasks: Arc::new(tasks),
-            progress
-        }
+            progress,
+            failures
+        })
     }
 
     pub async fn queue(&self, download_entry: Box<Download>) -> anyhow::Result<()> {
@@ -67,73 +194,541 @@ impl Downloader {
         Ok(())
     }
 
-    async fn download_and_track(http_client: &Client, progress: Progress, dl: Box<Download>) -> anyhow::Result<()> {
-        match download_file(http_client, dl, 
-            |downloaded| progress.bytes.inc_success(downloaded)
-        ).await {
-            Ok(true) => progress.files.inc_success(1),
-            Ok(false) => progress.files.inc_skipped(1),
-            Err(_) => progress.files.inc_skipped(1),
+    async fn download_and_track(ctx: &WorkerCtx, mut dl: Box<Download>) {
+        let result = Downloader::download_with_retries(ctx, &dl).await;
+
+        if result.is_err() {
+            ctx.progress.files.inc_failed(1);
         }
 
-        Ok(())
+        // Whoever waits for the download gets the failure, it is only listed for the others.
+        match (dl.done.take(), result) {
+            (Some(done), result) => _ = done.send(result.map_err(|e| format!("{e:#}"))),
+            (None, Err(e)) => ctx.failures.lock().expect("failure list lock poisoned").push(FailedDownload {
+                url: dl.url.clone(),
+                reason: format!("{e:#}"),
+            }),
+            (None, Ok(())) => {}
+        }
+    }
+
+    async fn download_with_retries(ctx: &WorkerCtx, dl: &Download) -> anyhow::Result<()> {
+        let mut attempt = 0;
+
+        loop {
+            let result = download_file(&ctx.http_client, dl, &ctx.throttle, |downloaded| {
+                ctx.progress.bytes.inc_success(downloaded)
+            }).await;
+
+            let e = match result {
+                Ok(Some(digest)) => {
+                    if let Some(archive) = &dl.archive {
+                        ctx.manifest.insert(digest.into_entry(archive, chrono::Utc::now()));
+                    }
+
+                    ctx.progress.files.inc_success(1);
+
+                    return Ok(())
+                },
+                Ok(None) => {
+                    if let Some(archive) = &dl.archive && !ctx.manifest.contains(archive) {
+                        Downloader::backfill_manifest(ctx, archive, &dl.primary_target_path).await;
+                    }
+
+                    ctx.progress.files.inc_skipped(1);
+
+                    return Ok(())
+                },
+                Err(e) => e
+            };
+
+            if attempt < ctx.retry.retries && let Some(transient) = e.downcast_ref::<TransientError>() {
+                sleep(ctx.retry.backoff(attempt, transient.retry_after)).await;
+                attempt += 1;
+                continue
+            }
+
+            return Err(e)
+        }
+    }
+
+    /// Hashes an archive that was mirrored before it had a manifest entry.
+    async fn backfill_manifest(ctx: &WorkerCtx, archive: &ArchiveId, path: &str) {
+        let digest = match FileDigest::of_file(path).await {
+            Ok(digest) => digest,
+            Err(e) => return crate::log(format!("WARN hashing {path}: {e}"))
+        };
+
+        let fetched_at = tokio::fs::metadata(path).await
+            .and_then(|m| m.modified())
+            .map(chrono::DateTime::<chrono::Utc>::from)
+            .unwrap_or_else(|_| chrono::Utc::now());
+
+        ctx.manifest.insert(digest.into_entry(archive, fetched_at));
     }
 
     pub fn progress(&self) -> Progress {
         self.progress.clone()
     }
+
+    /// Drains the downloads without a `done` channel that failed permanently since the last call.
+    pub fn take_failures(&self) -> Vec<FailedDownload> {
+        std::mem::take(&mut *self.failures.lock().expect("failure list lock poisoned"))
+    }
+}
+
+/// Size and SHA-256 of a downloaded file.
+pub struct FileDigest {
+    pub size: u64,
+    pub sha256: String,
 }
 
-async fn download_file<F>(http_client: &Client, download: Box<Download>, mut progress_cb: F) -> anyhow::Result<bool>
+impl FileDigest {
+    pub async fn of_file<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        let mut hasher = Sha256::new();
+
+        let size = hash_file(&mut hasher, path.as_ref()).await?;
+
+        Ok(Self { size, sha256: hex::encode(hasher.finalize()) })
+    }
+
+    fn into_entry(self, archive: &ArchiveId, fetched_at: chrono::DateTime<chrono::Utc>) -> ManifestEntry {
+        ManifestEntry {
+            id: archive.id.clone(),
+            version: archive.version.clone(),
+            size: self.size,
+            sha256: self.sha256,
+            fetched_at: fetched_at.to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+        }
+    }
+}
+
+async fn hash_file(hasher: &mut Sha256, path: &Path) -> anyhow::Result<u64> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut buf = vec![0_u8; 64 * 1024];
+    let mut size = 0_u64;
+
+    loop {
+        let read = file.read(&mut buf).await?;
+
+        if read == 0 {
+            return Ok(size)
+        }
+
+        hasher.update(&buf[..read]);
+        size += read as u64;
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct FailedDownload {
+    pub url: String,
+    pub reason: String,
+}
+
+#[derive(Clone, Copy)]
+struct RetryPolicy {
+    retries: u32,
+    base: Duration,
+    max: Duration,
+}
+
+impl From<&DownloaderOpts> for RetryPolicy {
+    fn from(opts: &DownloaderOpts) -> Self {
+        Self {
+            retries: opts.retries,
+            base: Duration::from_millis(opts.retry_backoff_ms),
+            max: Duration::from_millis(opts.retry_max_backoff_ms),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Exponential backoff with jitter. A `Retry-After` from the server is a lower bound.
+    fn backoff(&self, attempt: u32, retry_after: Option<Duration>) -> Duration {
+        let capped = self.base
+            .saturating_mul(2_u32.saturating_pow(attempt))
+            .min(self.max);
+
+        let half = capped.as_millis() as u64 / 2;
+        let jittered = Duration::from_millis(half + fastrand::u64(0..=half));
+
+        match retry_after {
+            Some(retry_after) => retry_after.max(jittered),
+            None => jittered
+        }
+    }
+}
+
+/// An error that is worth retrying: connection problems, timeouts and 429/5xx responses.
+#[derive(Debug)]
+pub struct TransientError {
+    reason: String,
+    retry_after: Option<Duration>,
+}
+
+impl Display for TransientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.reason)
+    }
+}
+
+impl std::error::Error for TransientError {}
+
+fn classify_reqwest_error(url: &str, e: reqwest::Error) -> anyhow::Error {
+    if e.is_connect() || e.is_timeout() || e.is_body() || e.is_request() {
+        return TransientError { reason: format!("{url}: {e}"), retry_after: None }.into()
+    }
+
+    anyhow::Error::new(e).context(format!("downloading {url}"))
+}
+
+fn parse_retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds))
+    }
+
+    let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+
+    (date.with_timezone(&chrono::Utc) - chrono::Utc::now()).to_std().ok()
+}
+
+/// Returns the digest of the file if it was downloaded, or `None` if it was already present.
+async fn download_file<F>(http_client: &Client, download: &Download, throttle: &Throttle, mut progress_cb: F) -> anyhow::Result<Option<FileDigest>>
     where F: FnMut(u64) {
     
-    let mut downloaded = false;
+    let mut downloaded = None;
 
-    if needs_downloading(&download) {
+    if needs_downloading(download) {
         create_dirs(&download.primary_target_path).await?;
 
-        let mut output = tokio::fs::File::create(&download.primary_target_path).await?;
-
         if download.size.is_some_and(|v| v > 0) || download.size.is_none() {
-            let mut response = http_client.get(download.url.as_str()).send().await
-                .with_context(|| format!("downloading {}", download.url.clone()))?;
+            let part_path = part_path(&download.primary_target_path);
+            let state_path = part_state_path(&download.primary_target_path);
+
+            // The extension list changes between runs, so only files that are never rewritten are resumed.
+            let resumable = !download.always_download;
 
-            if response.status() == StatusCode::NOT_FOUND {
-                drop(output);
-                tokio::fs::remove_file(&download.primary_target_path).await?;
+            let resume = if resumable {
+                PartState::load(&state_path, &part_path, &download.url).await
+            } else {
+                None
+            };
 
-                bail!("{}: 404", download.url.clone())
+            let mut request = http_client.get(download.url.as_str());
+
+            if resumable {
+                // Byte ranges have to refer to the stored representation, not a transfer-encoded one.
+                request = request.header(ACCEPT_ENCODING, "identity");
             }
 
-            while let Some(chunk) = response.chunk().await? {
-                output.write_all(&chunk).await?;
-        
-                progress_cb(chunk.len() as u64);
+            if let Some((state, offset)) = &resume {
+                request = request
+                    .header(RANGE, format!("bytes={offset}-"))
+                    .header(IF_RANGE, state.validator());
             }
-        
-            output.flush().await?;
-            downloaded = true;
-        }
 
-        if let Some(symlink_path) = &download.symlink_path {
-            create_dirs(symlink_path).await?;
-            
-            let symlink_path = PathBuf::from(symlink_path);
+            let mut response = request.send().await
+                .map_err(|e| classify_reqwest_error(&download.url, e))?;
+
+            let status = response.status();
+
+            if status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error() {
+                return Err(TransientError {
+                    reason: format!("{}: {status}", download.url),
+                    retry_after: parse_retry_after(response.headers())
+                }.into())
+            }
+
+            if status == StatusCode::RANGE_NOT_SATISFIABLE {
+                discard_part(&part_path, &state_path).await;
+
+                return Err(TransientError {
+                    reason: format!("{}: partial download no longer matches, restarting", download.url),
+                    retry_after: None
+                }.into())
+            }
+
+            if !status.is_success() {
+                bail!("{}: {status}", download.url.clone())
+            }
+
+            let (offset, expected_size) = match resume {
+                Some((_, offset)) if status == StatusCode::PARTIAL_CONTENT => {
+                    let Some((start, total)) = parse_content_range(response.headers()) else {
+                        discard_part(&part_path, &state_path).await;
+                        bail!("{}: invalid Content-Range in partial response", download.url)
+                    };
+
+                    if start != offset {
+                        discard_part(&part_path, &state_path).await;
+
+                        return Err(TransientError {
+                            reason: format!("{}: server resumed at byte {start} instead of {offset}", download.url),
+                            retry_after: None
+                        }.into())
+                    }
+
+                    (offset, total)
+                },
+                _ => {
+                    let state = PartState::from_response(&download.url, &response);
+
+                    match state {
+                        Some(state) if resumable => state.save(&state_path).await?,
+                        _ => _ = remove_file(&state_path).await
+                    }
+
+                    (0, response.content_length().or(download.size))
+                }
+            };
+
+            let mut hasher = Sha256::new();
+
+            if offset > 0 {
+                hash_file(&mut hasher, &part_path).await?;
+            }
+
+            let written = match write_part(&mut response, &download.url, &part_path, offset > 0, &mut hasher, throttle, &mut progress_cb).await {
+                Ok(written) => written,
+                Err(e) => {
+                    if !resumable {
+                        _ = remove_file(&part_path).await;
+                    }
+
+                    return Err(e)
+                }
+            };
+
+            let size = offset + written;
+
+            if let Some(expected_size) = expected_size && size != expected_size {
+                if !resumable || size > expected_size {
+                    discard_part(&part_path, &state_path).await;
+                }
+
+                return Err(TransientError {
+                    reason: format!("{}: received {size} of {expected_size} bytes", download.url),
+                    retry_after: None
+                }.into())
+            }
+
+            commit_part(&part_path, &download.primary_target_path).await?;
 
-            let rel_primary_path = pathdiff::diff_paths(
-                &download.primary_target_path,
-                symlink_path.parent().expect("base dir needs to exist"),
-            ).expect("all files will be in some relative path");
+            _ = remove_file(&state_path).await;
 
-            _ = remove_file(&symlink_path).await;
+            downloaded = Some(FileDigest { size, sha256: hex::encode(hasher.finalize()) });
+        } else {
+            tokio::fs::File::create(&download.primary_target_path).await?;
 
-            symlink(&rel_primary_path, symlink_path).await?;
+            downloaded = Some(FileDigest { size: 0, sha256: hex::encode(Sha256::digest([])) });
+        }
+
+        if let Some(symlink_path) = &download.symlink_path {
+            link_latest(&download.primary_target_path, symlink_path).await?;
         }
     }
     
     Ok(downloaded)
 }
 
+/// Atomically points `symlink_path` at `primary_path` through a relative symlink.
+pub async fn link_latest(primary_path: &str, symlink_path: &str) -> anyhow::Result<()> {
+    create_dirs(symlink_path).await?;
+    
+    let symlink_path = PathBuf::from(symlink_path);
+
+    let rel_primary_path = pathdiff::diff_paths(
+        primary_path,
+        symlink_path.parent().expect("base dir needs to exist"),
+    ).expect("all files will be in some relative path");
+
+    let tmp_symlink_path = part_path(&symlink_path);
+
+    _ = remove_file(&tmp_symlink_path).await;
+
+    symlink(&rel_primary_path, &tmp_symlink_path).await?;
+
+    tokio::fs::rename(&tmp_symlink_path, symlink_path).await?;
+
+    Ok(())
+}
+
+/// Validators of a partially downloaded file, stored next to it so a later attempt can
+/// continue with a conditional range request.
+#[derive(Serialize, Deserialize)]
+struct PartState {
+    url: String,
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+impl PartState {
+    fn from_response(url: &str, response: &Response) -> Option<Self> {
+        let header = |name| response.headers().get(name)
+            .and_then(|v: &HeaderValue| v.to_str().ok())
+            .map(str::to_owned);
+
+        // Weak validators are not allowed in If-Range.
+        let etag = header(ETAG).filter(|v| !v.starts_with("W/"));
+        let last_modified = header(LAST_MODIFIED);
+
+        if etag.is_none() && last_modified.is_none() {
+            return None
+        }
+
+        Some(Self { url: url.to_owned(), etag, last_modified })
+    }
+
+    /// Returns the stored state and the number of bytes already on disk, if the
+    /// partial download can be continued.
+    async fn load(state_path: &Path, part_path: &Path, url: &str) -> Option<(Self, u64)> {
+        let state: PartState = serde_json::from_slice(&tokio::fs::read(state_path).await.ok()?).ok()?;
+
+        if state.url != url {
+            return None
+        }
+
+        let offset = tokio::fs::metadata(part_path).await.ok()?.len();
+
+        (offset > 0).then_some((state, offset))
+    }
+
+    async fn save(&self, state_path: &Path) -> anyhow::Result<()> {
+        tokio::fs::write(state_path, serde_json::to_vec(self)?).await?;
+
+        Ok(())
+    }
+
+    fn validator(&self) -> &str {
+        self.etag.as_deref()
+            .or(self.last_modified.as_deref())
+            .expect("state is only stored with a validator")
+    }
+}
+
+/// Parses `Content-Range: bytes start-end/total` into the start offset and total length.
+fn parse_content_range(headers: &HeaderMap) -> Option<(u64, Option<u64>)> {
+    let value = headers.get(CONTENT_RANGE)?.to_str().ok()?;
+
+    let (range, total) = value.strip_prefix("bytes ")?.split_once('/')?;
+    let (start, _) = range.split_once('-')?;
+
+    Some((start.parse().ok()?, total.parse().ok()))
+}
+
+async fn discard_part(part_path: &Path, state_path: &Path) {
+    _ = remove_file(part_path).await;
+    _ = remove_file(state_path).await;
+}
+
+/// Streams the response body into the temporary file and makes sure it has hit the disk.
+/// Returns the number of bytes written by this call.
+async fn write_part<F>(response: &mut Response, url: &str, part_path: &Path, append: bool, hasher: &mut Sha256, throttle: &Throttle, progress_cb: &mut F) -> anyhow::Result<u64>
+    where F: FnMut(u64) {
+    let mut output = tokio::fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .append(append)
+        .truncate(!append)
+        .open(part_path).await?;
+
+    let mut written = 0_u64;
+
+    let result = async {
+        while let Some(chunk) = response.chunk().await
+            .map_err(|e| classify_reqwest_error(url, e))? {
+            output.write_all(&chunk).await?;
+            hasher.update(&chunk);
+
+            written += chunk.len() as u64;
+
+            progress_cb(chunk.len() as u64);
+
+            throttle.consume(chunk.len() as u64).await;
+        }
+
+        anyhow::Ok(())
+    }.await;
+
+    // Whatever made it to the file is kept, so that a resumed download continues from it.
+    output.flush().await?;
+    output.sync_all().await?;
+
+    result.map(|_| written)
+}
+
+/// Atomically moves a completed temporary file into place.
+async fn commit_part(part_path: &Path, target_path: &str) -> anyhow::Result<()> {
+    tokio::fs::rename(part_path, target_path).await?;
+
+    if let Some(parent_dir) = Path::new(target_path).parent() {
+        tokio::fs::File::open(parent_dir).await?
+            .sync_all().await?;
+    }
+
+    Ok(())
+}
+
+/// The temporary sibling a download is written to before it is renamed into place.
+pub fn part_path<P: AsRef<Path>>(path: P) -> PathBuf {
+    let mut part_path = path.as_ref().as_os_str().to_owned();
+    part_path.push(PART_SUFFIX);
+
+    PathBuf::from(part_path)
+}
+
+fn part_state_path<P: AsRef<Path>>(path: P) -> PathBuf {
+    let mut state_path = path.as_ref().as_os_str().to_owned();
+    state_path.push(PART_STATE_SUFFIX);
+
+    PathBuf::from(state_path)
+}
+
+/// Removes temporary files left behind by interrupted runs that cannot be resumed.
+/// Returns the number of removed files.
+pub async fn remove_partial_downloads<P: AsRef<Path>>(dir: P) -> anyhow::Result<u64> {
+    let mut removed = 0;
+    let mut dirs = vec![dir.as_ref().to_path_buf()];
+
+    while let Some(dir) = dirs.pop() {
+        let mut entries = match tokio::fs::read_dir(&dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into())
+        };
+
+        while let Some(entry) = entries.next_entry().await? {
+            let file_type = entry.file_type().await?;
+
+            if file_type.is_dir() {
+                dirs.push(entry.path());
+                continue
+            }
+
+            let file_name = entry.file_name();
+            let file_name = file_name.to_string_lossy();
+
+            let orphaned = if let Some(target) = file_name.strip_suffix(PART_SUFFIX) {
+                !tokio::fs::try_exists(part_state_path(dir.join(target))).await?
+            } else if let Some(target) = file_name.strip_suffix(PART_STATE_SUFFIX) {
+                !tokio::fs::try_exists(part_path(dir.join(target))).await?
+            } else {
+                false
+            };
+
+            if orphaned {
+                remove_file(entry.path()).await?;
+                removed += 1;
+            }
+        }
+    }
+
+    Ok(removed)
+}
+
 pub async fn create_dirs<P: AsRef<Path>>(path: P) -> anyhow::Result<()> {
     if let Some(parent_dir) = path.as_ref().parent() && !parent_dir.exists()  {
         tokio::fs::create_dir_all(parent_dir).await?;
@@ -161,4 +756,76 @@ pub struct Download {
     pub primary_target_path: String,
     pub symlink_path: Option<String>,
     pub always_download: bool,
-}
\ No newline at end of file
+    pub archive: Option<ArchiveId>,
+    /// Receives the outcome once the download has finished, failed or was skipped. A failure is
+    /// then not listed by `take_failures`.
+    pub done: Option<oneshot::Sender<Result<(), String>>>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn content_range(value: &str) -> Option<(u64, Option<u64>)> {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_RANGE, HeaderValue::from_str(value).unwrap());
+
+        parse_content_range(&headers)
+    }
+
+    #[test]
+    fn content_range_with_total() {
+        assert_eq!(content_range("bytes 100-199/1000"), Some((100, Some(1000))));
+        assert_eq!(content_range("bytes 0-0/1"), Some((0, Some(1))));
+    }
+
+    #[test]
+    fn content_range_with_unknown_total() {
+        assert_eq!(content_range("bytes 100-199/*"), Some((100, None)));
+    }
+
+    #[test]
+    fn content_range_invalid() {
+        assert_eq!(content_range("bytes */1000"), None);
+        assert_eq!(content_range("items 0-9/10"), None);
+        assert_eq!(content_range("bytes 0-9"), None);
+        assert_eq!(content_range("bytes x-9/10"), None);
+        assert_eq!(parse_content_range(&HeaderMap::new()), None);
+    }
+
+    fn retry_policy(base_ms: u64, max_ms: u64) -> RetryPolicy {
+        RetryPolicy {
+            retries: 3,
+            base: Duration::from_millis(base_ms),
+            max: Duration::from_millis(max_ms),
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_with_jitter() {
+        let policy = retry_policy(100, 10_000);
+
+        for (attempt, full) in [(0, 100), (1, 200), (2, 400), (3, 800)] {
+            let backoff = policy.backoff(attempt, None).as_millis() as u64;
+            assert!((full / 2..=full).contains(&backoff), "attempt {attempt} waited {backoff}ms");
+        }
+    }
+
+    #[test]
+    fn backoff_is_capped() {
+        let policy = retry_policy(100, 1000);
+
+        for attempt in [4, 10, 40] {
+            let backoff = policy.backoff(attempt, None).as_millis() as u64;
+            assert!((500..=1000).contains(&backoff), "attempt {attempt} waited {backoff}ms");
+        }
+    }
+
+    #[test]
+    fn backoff_honours_retry_after() {
+        let policy = retry_policy(100, 1000);
+
+        assert_eq!(policy.backoff(0, Some(Duration::from_secs(5))), Duration::from_secs(5));
+        assert!(policy.backoff(3, Some(Duration::ZERO)) >= Duration::from_millis(400));
+    }
+}
@@ -1,10 +1,28 @@
-use tantivy::{collector::TopDocs, query::{BooleanQuery, Occur, Query, RangeQuery, TermQuery}, schema::IndexRecordOption, Index, IndexReader, Term};
+use std::collections::{hash_map::Entry, HashMap};
 
-use crate::{package_meta::ExtensionMetadata, serve::extensions::{GetExtensionUpdatesParams, GetExtensionVersionsParams, GetExtensionsParams}};
+use tantivy::{collector::{DocSetCollector, TopDocs}, columnar::{Column, StrColumn}, query::{AllQuery, BooleanQuery, BoostQuery, FuzzyTermQuery, Occur, Query, RangeQuery, TermQuery}, schema::{document::Document, IndexRecordOption, Schema}, DocAddress, DocId, Index, IndexReader, Searcher, SegmentOrdinal, SegmentReader, TantivyDocument, Term};
 
+use crate::{index::ID_TEXT_FIELD, package_meta::{cmp_versions, ExtensionMetadata, WasmApiBounds}, serve::extensions::{GetExtensionUpdatesParams, GetExtensionVersionsParams, GetExtensionsParams}};
+
+/// Fields searched by a filter and how much a match in each counts.
+const FILTER_FIELDS: [(&str, f32); 3] = [(ID_TEXT_FIELD, 3.0), ("name", 2.0), ("description", 1.0)];
+
+/// How much more an exact word counts than a prefix or a misspelling of it.
+const EXACT_BOOST: f32 = 2.0;
+
+/// How many extensions `/extensions` returns when the request sets no limit.
+const DEFAULT_EXTENSIONS_LIMIT: usize = 1000;
+
+/// Searches one or more indexes as if they were one.
 #[derive(Clone)]
 pub struct ExtSearcher {
+    sources: Vec<Source>,
+}
+
+#[derive(Clone)]
+struct Source {
     index: Index,
+    schema: Schema,
     reader: IndexReader,
 }
 
@@ -13,81 +31,241 @@ impl ExtSearcher {
         let reader = index.reader()?;
 
         Ok(Self {
-            index,
-            reader
+            sources: vec![Source {
+                schema: index.schema(),
+                index,
+                reader
+            }]
         })
     }
 
-    pub fn get_extension_updates(&self, params: &GetExtensionUpdatesParams) -> anyhow::Result<Vec<ExtensionMetadata>> {
-        let searcher = self.reader.searcher();
+    /// Searches the indexes of `other` too, e.g. the pulled-through archives next to a generation.
+    pub fn overlaid(mut self, other: &ExtSearcher) -> Self {
+        self.sources.extend(other.sources.iter().cloned());
 
-        let mut sub_queries: Vec<(Occur, Box<dyn Query>)> = Vec::new();
-        
-        self.add_query_from_ids(&mut sub_queries, &params.ids)?;
+        self
+    }
 
-        self.add_query_from_schema_version_range(&mut sub_queries, Some(params.min_schema_version), params.max_schema_version)?;
+    /// The first index searched.
+    pub fn index(&self) -> &Index {
+        &self.sources[0].index
+    }
 
-        let top_docs = searcher.search(&(Box::new(BooleanQuery::new(sub_queries)) as Box<dyn Query>), &TopDocs::with_limit(1000))?;
+    /// Makes documents committed since the last reload visible to searches.
+    pub fn reload(&self) -> anyhow::Result<()> {
+        for source in &self.sources {
+            source.reader.reload()?;
+        }
 
+        Ok(())
+    }
+
+    /// The newest version of each extension that fits the schema and wasm API versions of the client.
+    pub fn get_extension_updates(&self, params: &GetExtensionUpdatesParams, wasm_api: &WasmApiBounds) -> anyhow::Result<Vec<ExtensionMetadata>> {
         let mut data = Vec::new();
-        
-        for (_score, doc_address) in top_docs {
-            let doc: ExtensionMetadata = searcher.doc(doc_address)?;
 
-            data.push(doc);
+        for source in &self.sources {
+            let searcher = source.reader.searcher();
+
+            let mut sub_queries: Vec<(Occur, Box<dyn Query>)> = Vec::new();
+            
+            source.add_query_from_ids(&mut sub_queries, &params.ids)?;
+
+            source.add_query_from_schema_version_range(&mut sub_queries, Some(params.min_schema_version), params.max_schema_version)?;
+
+            let top_docs = searcher.search(&(Box::new(BooleanQuery::new(sub_queries)) as Box<dyn Query>), &TopDocs::with_limit(all_docs_limit(&searcher)))?;
+
+            for (_score, doc_address) in top_docs {
+                let doc = source.doc(&searcher, doc_address)?;
+
+                // Filtered before picking the newest, so an older version that fits is offered instead.
+                if wasm_api.allows(&doc) {
+                    data.push(doc);
+                }
+            }
         }
 
-        Ok(data)
+        Ok(newest_per_id(data, |doc| (&doc.id, &doc.version)))
     }
 
     pub fn get_extension_versions(&self, params: &GetExtensionVersionsParams) -> anyhow::Result<Vec<ExtensionMetadata>> {
-        let searcher = self.reader.searcher();
+        let mut data = Vec::new();
 
-        let mut sub_queries: Vec<(Occur, Box<dyn Query>)> = Vec::new();
-        
-        self.add_query_from_ids(&mut sub_queries, &params.extension_id)?;
+        for source in &self.sources {
+            let searcher = source.reader.searcher();
 
-        let top_docs = searcher.search(&(Box::new(BooleanQuery::new(sub_queries)) as Box<dyn Query>), &TopDocs::with_limit(1000))?;
+            let mut sub_queries: Vec<(Occur, Box<dyn Query>)> = Vec::new();
+            
+            source.add_query_from_ids(&mut sub_queries, &params.extension_id)?;
 
-        let mut data = Vec::new();
-        
-        for (_score, doc_address) in top_docs {
-            let doc: ExtensionMetadata = searcher.doc(doc_address)?;
+            let top_docs = searcher.search(&(Box::new(BooleanQuery::new(sub_queries)) as Box<dyn Query>), &TopDocs::with_limit(1000))?;
+
+            for (_score, doc_address) in top_docs {
+                let doc = source.doc(&searcher, doc_address)?;
 
-            data.push(doc);
+                data.push(doc);
+            }
         }
 
+        data.sort_by(|a, b| cmp_versions(&b.version, &a.version));
+
+        // A version can be in more than one index, the first one wins.
+        data.dedup_by(|a, b| a.id == b.id && a.version == b.version);
+
         Ok(data)
     }
 
-    pub fn get_extensions(&self, params: &GetExtensionsParams) -> anyhow::Result<Vec<ExtensionMetadata>> {
-        let searcher = self.reader.searcher();
+    /// Every indexed extension version, in no particular order.
+    pub fn get_all_extensions(&self) -> anyhow::Result<Vec<ExtensionMetadata>> {
+        let mut data = Vec::new();
 
-        let mut sub_queries: Vec<(Occur, Box<dyn Query>)> = Vec::new();
-        
-        if let Some(filter) = &params.filter {
-            self.add_query_from_filter(&mut sub_queries, filter)?;
+        for source in &self.sources {
+            let searcher = source.reader.searcher();
+
+            for doc_address in searcher.search(&AllQuery, &DocSetCollector)? {
+                let doc = source.doc(&searcher, doc_address)?;
+
+                data.push(doc);
+            }
         }
 
-        if let Some(provides_filter) = &params.provides {
-            self.add_query_from_provides(&mut sub_queries, provides_filter)?;
+        Ok(data)
+    }
+
+    /// The newest version of every matching extension, most relevant first when filtering and most
+    /// downloaded first otherwise, along with the number of matches before pagination. Matches are
+    /// ranked by their fast fields, only the documents of the requested page are loaded.
+    pub fn get_extensions(&self, params: &GetExtensionsParams) -> anyhow::Result<(Vec<ExtensionMetadata>, usize)> {
+        let mut hits = Vec::new();
+        let mut scores: HashMap<String, f32> = HashMap::new();
+
+        // Kept, so that the page is loaded from the same state of the indexes that was searched.
+        let searchers: Vec<Searcher> = self.sources.iter().map(|source| source.reader.searcher()).collect();
+
+        for (source_ord, (source, searcher)) in self.sources.iter().zip(&searchers).enumerate() {
+            let mut sub_queries: Vec<(Occur, Box<dyn Query>)> = Vec::new();
+            
+            if let Some(filter) = &params.filter {
+                source.add_query_from_filter(&mut sub_queries, filter)?;
+            }
+
+            if let Some(provides_filter) = &params.provides {
+                source.add_query_from_provides(&mut sub_queries, provides_filter)?;
+            }
+
+            source.add_query_from_schema_version_range(&mut sub_queries, None, params.max_schema_version)?;
+
+            for hit in source.hits(source_ord, searcher, &BooleanQuery::new(sub_queries))? {
+                // Without a filter every document scores the same, so only downloads decide.
+                if params.filter.is_some() {
+                    let best = scores.entry(hit.id.clone()).or_insert(hit.score);
+                    *best = best.max(hit.score);
+                }
+
+                hits.push(hit);
+            }
         }
 
-        self.add_query_from_schema_version_range(&mut sub_queries, None, params.max_schema_version)?;
+        let mut hits = newest_per_id(hits, |hit| (&hit.id, &hit.version));
 
-        let top_docs = searcher.search(&(Box::new(BooleanQuery::new(sub_queries)) as Box<dyn Query>), &TopDocs::with_limit(1000))?;
+        let score = |hit: &Hit| scores.get(&hit.id).copied().unwrap_or_default();
 
-        let mut data = Vec::new();
-        
-        for (_score, doc_address) in top_docs {
-            let doc: ExtensionMetadata = searcher.doc(doc_address)?;
+        hits.sort_by(|a, b| score(b).total_cmp(&score(a))
+            .then(b.download_count.cmp(&a.download_count))
+            .then_with(|| a.id.cmp(&b.id)));
+
+        let total = hits.len();
+
+        let data = hits.into_iter()
+            .skip(params.offset)
+            .take(params.limit.unwrap_or(DEFAULT_EXTENSIONS_LIMIT))
+            .map(|hit| self.sources[hit.source_ord].doc(&searchers[hit.source_ord], hit.address))
+            .collect::<anyhow::Result<_>>()?;
 
-            data.push(doc);
+        Ok((data, total))
+    }
+}
+
+/// What ranking and deduplicating a match needs, without its stored document.
+struct Hit {
+    source_ord: usize,
+    address: DocAddress,
+    score: f32,
+    id: String,
+    version: String,
+    download_count: u64,
+}
+
+/// The fast fields of a segment that a `Hit` is read from.
+struct HitColumns {
+    id: StrColumn,
+    version: StrColumn,
+    download_count: Column<u64>,
+}
+
+impl HitColumns {
+    /// `None` for indexes from before the fields were fast, e.g. one served with
+    /// `--on-schema-mismatch warn`.
+    fn open(segment_reader: &SegmentReader) -> Option<Self> {
+        let fast_fields = segment_reader.fast_fields();
+
+        Some(Self {
+            id: fast_fields.str("id").ok().flatten()?,
+            version: fast_fields.str("version").ok().flatten()?,
+            download_count: fast_fields.u64("download_count").ok()?,
+        })
+    }
+
+    fn str_value(column: &StrColumn, doc: DocId) -> anyhow::Result<String> {
+        let mut value = String::new();
+
+        if let Some(ord) = column.term_ords(doc).next() {
+            column.ord_to_str(ord, &mut value)?;
         }
 
-        Ok(data)
+        Ok(value)
+    }
+}
+
+impl Source {
+    /// Every match of `query` in this index, in no particular order.
+    fn hits(&self, source_ord: usize, searcher: &Searcher, query: &dyn Query) -> anyhow::Result<Vec<Hit>> {
+        let top_docs = searcher.search(query, &TopDocs::with_limit(all_docs_limit(searcher)))?;
+
+        let mut columns: HashMap<SegmentOrdinal, Option<HitColumns>> = HashMap::new();
+        let mut hits = Vec::with_capacity(top_docs.len());
+
+        for (score, address) in top_docs {
+            let segment_columns = match columns.entry(address.segment_ord) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => entry.insert(HitColumns::open(searcher.segment_reader(address.segment_ord)))
+            };
+
+            let (id, version, download_count) = match segment_columns {
+                Some(c) => (
+                    HitColumns::str_value(&c.id, address.doc_id)?,
+                    HitColumns::str_value(&c.version, address.doc_id)?,
+                    c.download_count.first(address.doc_id).unwrap_or_default(),
+                ),
+                None => {
+                    let doc = self.doc(searcher, address)?;
+
+                    (doc.id, doc.version, doc.download_count)
+                }
+            };
+
+            hits.push(Hit { source_ord, address, score, id, version, download_count });
+        }
+
+        Ok(hits)
     }
-    
+
+    fn doc(&self, searcher: &Searcher, doc_address: DocAddress) -> anyhow::Result<ExtensionMetadata> {
+        let doc: TantivyDocument = searcher.doc(doc_address)?;
+
+        ExtensionMetadata::from_named_doc(doc.to_named_doc(&self.schema))
+    }
+
     fn add_query_from_schema_version_range(&self, sub_queries: &mut Vec<(Occur, Box<dyn Query>)>, min_schema_version: Option<i32>, max_schema_version: i32) -> anyhow::Result<()> {
         let schema_version_field = self.index.schema().get_field("schema_version")?;
 
@@ -138,19 +316,92 @@ impl ExtSearcher {
         Ok(())
     }
 
+    /// Every word of the filter has to match the id, name or description, either exactly, as a
+    /// prefix, or with a few typos. The whole filter matching an id ranks that extension first.
     fn add_query_from_filter(&self, sub_queries: &mut Vec<(Occur, Box<dyn Query>)>, filter: &str) -> anyhow::Result<()> {
-        let id_field = self.index.schema().get_field("id")?;
-        let name_field = self.index.schema().get_field("name")?;
-        
-        sub_queries.push((Occur::Must, Box::new(BooleanQuery::new(vec![
-            (Occur::Must, Box::new(BooleanQuery::new(
-                vec![
-                    (Occur::Should, Box::new(TermQuery::new(Term::from_field_text(name_field, filter), IndexRecordOption::Basic))),
-                    (Occur::Should, Box::new(TermQuery::new(Term::from_field_text(id_field, &filter.to_lowercase()), IndexRecordOption::Basic)))
-                ]
-            )))
-        ]))));
+        let schema = self.index.schema();
+        let id_field = schema.get_field("id")?;
+        let name_field = schema.get_field("name")?;
+
+        let mut words = Vec::new();
+        let mut tokenizer = self.index.tokenizer_for_field(name_field)?;
+        let mut tokens = tokenizer.token_stream(filter);
+
+        tokens.process(&mut |token| words.push(token.text.clone()));
+
+        // Indexes written before the id was split into words do not have the field.
+        let fields: Vec<_> = FILTER_FIELDS.iter()
+            .filter_map(|(name, boost)| schema.get_field(name).ok().map(|field| (field, *boost)))
+            .collect();
+
+        let word_queries = words.iter()
+            .map(|word| {
+                let distance = typo_distance(word);
+
+                let matches = fields.iter()
+                    .flat_map(|&(field, boost)| {
+                        let term = Term::from_field_text(field, word);
+
+                        [
+                            (Occur::Should, Box::new(BoostQuery::new(Box::new(TermQuery::new(term.clone(), IndexRecordOption::WithFreqs)), boost * EXACT_BOOST)) as Box<dyn Query>),
+                            (Occur::Should, Box::new(BoostQuery::new(Box::new(FuzzyTermQuery::new_prefix(term, distance, true)), boost)) as Box<dyn Query>),
+                        ]
+                    })
+                    .collect();
+
+                (Occur::Must, Box::new(BooleanQuery::new(matches)) as Box<dyn Query>)
+            })
+            .collect::<Vec<(Occur, Box<dyn Query>)>>();
+
+        let mut filter_queries: Vec<(Occur, Box<dyn Query>)> = vec![
+            (Occur::Should, Box::new(BoostQuery::new(Box::new(TermQuery::new(Term::from_field_text(id_field, &filter.trim().to_lowercase()), IndexRecordOption::Basic)), 10.0)))
+        ];
+
+        if !word_queries.is_empty() {
+            filter_queries.push((Occur::Should, Box::new(BooleanQuery::new(word_queries))));
+        }
+
+        sub_queries.push((Occur::Must, Box::new(BooleanQuery::new(filter_queries))));
 
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+/// The index may hold several versions per extension, so searches that pick one
+/// version per id have to see every match before deduplicating.
+fn all_docs_limit(searcher: &Searcher) -> usize {
+    (searcher.num_docs() as usize).max(1)
+}
+
+/// How many typos a word of a filter may have: none for short words, which would otherwise
+/// match almost anything.
+fn typo_distance(word: &str) -> u8 {
+    match word.chars().count() {
+        0..=3 => 0,
+        4..=6 => 1,
+        _ => 2
+    }
+}
+
+/// Keeps the newest version of every extension, at the position where the extension
+/// first occurred. `key` gives the id and version of an entry.
+fn newest_per_id<T>(docs: Vec<T>, key: impl Fn(&T) -> (&str, &str)) -> Vec<T> {
+    let mut positions: HashMap<String, usize> = HashMap::new();
+    let mut newest: Vec<T> = Vec::with_capacity(docs.len());
+
+    for doc in docs {
+        let (id, version) = key(&doc);
+
+        match positions.get(id) {
+            Some(&i) => if cmp_versions(version, key(&newest[i]).1).is_gt() {
+                newest[i] = doc;
+            },
+            None => {
+                positions.insert(id.to_string(), newest.len());
+                newest.push(doc);
+            }
+        }
+    }
+
+    newest
+}
@@ -0,0 +1,195 @@
+use std::{collections::BTreeMap, fmt::Display, str::FromStr};
+
+use anyhow::bail;
+use globset::{GlobBuilder, GlobMatcher};
+use serde_json::{Map, Value};
+
+/// A glob on one metadata field, written as `field:glob`. A bare glob matches the id.
+#[derive(Clone, Debug)]
+pub struct Rule {
+    field: RuleField,
+    pattern: String,
+    matcher: GlobMatcher,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum RuleField {
+    Id,
+    Authors,
+    Repository,
+    Provides,
+}
+
+impl RuleField {
+    fn name(&self) -> &'static str {
+        match self {
+            RuleField::Id => "id",
+            RuleField::Authors => "authors",
+            RuleField::Repository => "repository",
+            RuleField::Provides => "provides",
+        }
+    }
+}
+
+impl FromStr for Rule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (field, pattern) = match s.split_once(':') {
+            Some(("id", pattern)) => (RuleField::Id, pattern),
+            Some(("authors", pattern)) => (RuleField::Authors, pattern),
+            Some(("repository", pattern)) => (RuleField::Repository, pattern),
+            Some(("provides", pattern)) => (RuleField::Provides, pattern),
+            Some((field, _)) if !field.contains(['*', '?', '[', '{', '/']) =>
+                bail!("unknown field {field}, expected id, authors, repository or provides"),
+            _ => (RuleField::Id, s)
+        };
+
+        let matcher = GlobBuilder::new(pattern)
+            .case_insensitive(true)
+            .literal_separator(false)
+            .build()?
+            .compile_matcher();
+
+        Ok(Self { field, pattern: pattern.to_string(), matcher })
+    }
+}
+
+impl Display for Rule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.field.name(), self.pattern)
+    }
+}
+
+impl Rule {
+    fn matches(&self, extension: &Map<String, Value>) -> bool {
+        match extension.get(self.field.name()) {
+            Some(Value::String(s)) => self.matcher.is_match(s),
+            Some(Value::Array(values)) => values.iter()
+                .filter_map(|v| v.as_str())
+                .any(|s| self.matcher.is_match(s)),
+            _ => false
+        }
+    }
+}
+
+/// Decides which extensions are mirrored. With include rules, an extension has to match at
+/// least one of them. An extension matching any exclude rule is never mirrored.
+#[derive(Clone, Default)]
+pub struct ExtensionFilter {
+    include: Vec<Rule>,
+    exclude: Vec<Rule>,
+}
+
+impl ExtensionFilter {
+    pub fn new(include: Vec<Rule>, exclude: Vec<Rule>) -> Self {
+        Self { include, exclude }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty()
+    }
+
+    /// Returns why an extension is filtered out, or `None` if it is mirrored.
+    pub fn check(&self, extension: &Map<String, Value>) -> Option<String> {
+        if !self.include.is_empty() && !self.include.iter().any(|rule| rule.matches(extension)) {
+            return Some("not matching any include rule".to_string())
+        }
+
+        self.exclude.iter()
+            .find(|rule| rule.matches(extension))
+            .map(|rule| format!("excluded by {rule}"))
+    }
+
+    /// Removes filtered out extensions and returns their ids grouped by reason.
+    pub fn retain(&self, extensions: &mut Vec<Map<String, Value>>) -> BTreeMap<String, Vec<String>> {
+        let mut filtered: BTreeMap<String, Vec<String>> = BTreeMap::new();
+
+        extensions.retain(|extension| {
+            let Some(reason) = self.check(extension) else {
+                return true
+            };
+
+            let id = extension.get("id").and_then(|v| v.as_str()).unwrap_or_default();
+
+            filtered.entry(reason).or_default().push(id.to_string());
+
+            false
+        });
+
+        filtered
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn extension(value: Value) -> Map<String, Value> {
+        match value {
+            Value::Object(map) => map,
+            _ => unreachable!("extensions are objects")
+        }
+    }
+
+    fn rules(rules: &[&str]) -> Vec<Rule> {
+        rules.iter().map(|rule| rule.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn rule_fields() {
+        assert_eq!("python*".parse::<Rule>().unwrap().to_string(), "id:python*");
+        assert_eq!("id:python".parse::<Rule>().unwrap().to_string(), "id:python");
+        assert_eq!("authors:*Jane*".parse::<Rule>().unwrap().to_string(), "authors:*Jane*");
+        assert_eq!("repository:https://github.com/*".parse::<Rule>().unwrap().to_string(), "repository:https://github.com/*");
+        assert_eq!("provides:themes".parse::<Rule>().unwrap().to_string(), "provides:themes");
+    }
+
+    #[test]
+    fn rule_unknown_field() {
+        assert!("license:mit".parse::<Rule>().is_err());
+        // A colon after glob characters is part of a bare glob, not a field.
+        assert_eq!("*:x".parse::<Rule>().unwrap().to_string(), "id:*:x");
+    }
+
+    #[test]
+    fn rule_matches_case_insensitively_and_arrays() {
+        let ext = extension(json!({
+            "id": "Tailwind-CSS",
+            "authors": ["Jane Doe <jane@example.com>", "John Roe"],
+            "provides": ["language-servers"],
+        }));
+
+        assert!("tailwind*".parse::<Rule>().unwrap().matches(&ext));
+        assert!("authors:*JANE*".parse::<Rule>().unwrap().matches(&ext));
+        assert!("authors:john*".parse::<Rule>().unwrap().matches(&ext));
+        assert!("provides:language-servers".parse::<Rule>().unwrap().matches(&ext));
+        assert!(!"provides:themes".parse::<Rule>().unwrap().matches(&ext));
+        // A field the extension lacks matches nothing.
+        assert!(!"repository:*".parse::<Rule>().unwrap().matches(&ext));
+    }
+
+    #[test]
+    fn filter_precedence() {
+        let python = extension(json!({ "id": "python", "provides": ["languages"] }));
+        let theme = extension(json!({ "id": "python-theme", "provides": ["themes"] }));
+        let ruby = extension(json!({ "id": "ruby", "provides": ["languages"] }));
+
+        assert!(ExtensionFilter::default().check(&python).is_none());
+
+        let filter = ExtensionFilter::new(rules(&["python*"]), Vec::new());
+        assert!(filter.check(&python).is_none());
+        assert_eq!(filter.check(&ruby).as_deref(), Some("not matching any include rule"));
+
+        // Excludes win over includes.
+        let filter = ExtensionFilter::new(rules(&["python*"]), rules(&["provides:themes"]));
+        assert!(filter.check(&python).is_none());
+        assert_eq!(filter.check(&theme).as_deref(), Some("excluded by provides:themes"));
+
+        let filter = ExtensionFilter::new(Vec::new(), rules(&["ruby"]));
+        assert!(filter.check(&python).is_none());
+        assert_eq!(filter.check(&ruby).as_deref(), Some("excluded by id:ruby"));
+    }
+}
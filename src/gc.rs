@@ -0,0 +1,255 @@
+use std::{collections::{BTreeMap, BTreeSet}, path::{Path, PathBuf}, time::{Duration, SystemTime}};
+
+use anyhow::{bail, Context};
+use clap::Parser;
+use indicatif::HumanBytes;
+use tantivy::Index;
+
+use crate::{ext_searcher::ExtSearcher, generation::{current_path, list_generations}, lock::OutputLock, manifest::Manifest, package_meta::cmp_versions, pulled::PulledArchives};
+
+/// A run that would remove more than this share of the archives on disk is refused without
+/// `--force`, as that points to a broken index or a mistaken filter rather than to garbage.
+const MAX_GARBAGE_SHARE: f64 = 0.5;
+
+#[derive(Clone, Parser)]
+pub struct GcPolicy {
+    #[arg(long, help="Keep the latest N versions of every extension")]
+    pub keep_last: Option<usize>,
+    #[arg(long, help="Keep versions fetched within the last D days")]
+    pub keep_days: Option<u64>,
+}
+
+#[derive(Clone, Parser)]
+pub struct GcOpts {
+    #[command(flatten)]
+    pub policy: GcPolicy,
+    #[arg(long, help="Only list what would be removed")]
+    pub dry_run: bool,
+    #[arg(long, help="Collect garbage even if the index is empty or more than half of the archives would be removed")]
+    pub force: bool,
+}
+
+struct Garbage {
+    path: PathBuf,
+    id: String,
+    /// `None` when the whole extension is removed.
+    version: Option<String>,
+    /// Number of archive versions in `path`.
+    archives: usize,
+    bytes: u64,
+}
+
+pub async fn gc(opts: &GcOpts, mut output: &str) -> anyhow::Result<()> {
+    if let Some(path) = output.strip_suffix('/') {
+        output = path
+    }
+
+    let _lock = OutputLock::acquire(output, Duration::ZERO).await?;
+
+    collect_garbage(output, &opts.policy, opts.dry_run, opts.force).await
+}
+
+/// Removes extension versions that are neither referenced by the index of the current or a
+/// retained generation, nor pulled through, nor kept by the policy, and every extension that none
+/// of them know.
+/// Unless `force` is set, refuses to run on an empty index or to remove most of the archives.
+pub async fn collect_garbage(output: &str, policy: &GcPolicy, dry_run: bool, force: bool) -> anyhow::Result<()> {
+    let mut generation_paths = vec![current_path(output)];
+
+    for name in list_generations(output).await? {
+        generation_paths.push(PathBuf::from(format!("{output}/generations/{name}")));
+    }
+
+    let mut indexed: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+
+    for generation_path in &generation_paths {
+        let index = Index::open_in_dir(generation_path.join("idx"))
+            .with_context(|| format!("opening index in {}", generation_path.display()))?;
+
+        let searcher = ExtSearcher::init(index)?;
+
+        for ext in searcher.get_all_extensions().with_context(|| "reading index")? {
+            indexed.entry(ext.id).or_default().insert(ext.version);
+        }
+    }
+
+    for entry in PulledArchives::load(output).await?.manifest.entries() {
+        indexed.entry(entry.id).or_default().insert(entry.version);
+    }
+
+    let manifest_path = generation_paths[0].join("manifest.json");
+
+    let manifest = Manifest::load(&manifest_path).await
+        .with_context(|| "loading manifest")?;
+
+    let (garbage, archives) = find_garbage(output, &indexed, &manifest, policy).await
+        .with_context(|| "finding unreferenced versions")?;
+
+    let total_bytes: u64 = garbage.iter().map(|g| g.bytes).sum();
+    let garbage_archives: usize = garbage.iter().map(|g| g.archives).sum();
+
+    let refusal = if indexed.is_empty() && archives > 0 {
+        Some(String::from("the index is empty"))
+    } else if garbage_archives as f64 > archives as f64 * MAX_GARBAGE_SHARE {
+        Some(format!("{garbage_archives} of {archives} archives would be removed"))
+    } else {
+        None
+    };
+
+    if let Some(refusal) = &refusal && !force {
+        if !dry_run {
+            bail!("refusing to collect garbage, {refusal}. Check the index and filters, or use gc --force")
+        }
+
+        crate::log(format!("WARN {refusal}, gc would refuse to run without --force"));
+    }
+
+    let unindexed: Vec<&str> = garbage.iter()
+        .filter(|g| g.version.is_none())
+        .map(|g| g.id.as_str())
+        .collect();
+
+    if !unindexed.is_empty() {
+        crate::log(format!("{} extensions are not in any index, as they are no longer listed upstream or filtered out: {}",
+            unindexed.len(), unindexed.join(", ")));
+    }
+
+    for item in &garbage {
+        let what = match &item.version {
+            Some(version) => format!("{} {version}", item.id),
+            None => format!("{} (not in index)", item.id)
+        };
+
+        if dry_run {
+            println!("would remove {what}, {}", HumanBytes(item.bytes));
+            continue
+        }
+
+        tokio::fs::remove_dir_all(&item.path).await
+            .with_context(|| format!("removing {}", item.path.display()))?;
+
+        match &item.version {
+            Some(version) => manifest.remove(&item.id, version),
+            None => manifest.remove_extension(&item.id)
+        }
+
+        println!("removed {what}, {}", HumanBytes(item.bytes));
+    }
+
+    if dry_run {
+        crate::log(format!("Garbage collection would free {} in {} directories", HumanBytes(total_bytes), garbage.len()));
+        return Ok(())
+    }
+
+    if !garbage.is_empty() && tokio::fs::try_exists(&manifest_path).await? {
+        manifest.save(&manifest_path).await
+            .with_context(|| "writing manifest")?;
+    }
+
+    crate::log(format!("Garbage collection freed {} in {} directories", HumanBytes(total_bytes), garbage.len()));
+
+    Ok(())
+}
+
+/// The garbage, and the number of archive versions on disk.
+async fn find_garbage(output: &str, indexed: &BTreeMap<String, BTreeSet<String>>, manifest: &Manifest, policy: &GcPolicy) -> anyhow::Result<(Vec<Garbage>, usize)> {
+    let mut garbage = Vec::new();
+    let mut archives = 0;
+
+    let mut ids = match tokio::fs::read_dir(format!("{output}/extensions")).await {
+        Ok(ids) => ids,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok((garbage, archives)),
+        Err(e) => return Err(e.into())
+    };
+
+    let keep_since = policy.keep_days
+        .map(|days| SystemTime::now() - Duration::from_secs(days * 24 * 60 * 60));
+
+    while let Some(id_entry) = ids.next_entry().await? {
+        if !id_entry.file_type().await?.is_dir() {
+            continue
+        }
+
+        let id = id_entry.file_name().to_string_lossy().into_owned();
+
+        let mut versions = Vec::new();
+        let mut version_entries = tokio::fs::read_dir(id_entry.path()).await?;
+
+        while let Some(version_entry) = version_entries.next_entry().await? {
+            if version_entry.file_type().await?.is_dir() {
+                versions.push(version_entry);
+            }
+        }
+
+        archives += versions.len();
+
+        let Some(indexed_versions) = indexed.get(&id) else {
+            garbage.push(Garbage {
+                bytes: dir_size(&id_entry.path()).await?,
+                path: id_entry.path(),
+                id,
+                version: None,
+                archives: versions.len(),
+            });
+
+            continue
+        };
+
+        versions.sort_by(|a, b| cmp_versions(&b.file_name().to_string_lossy(), &a.file_name().to_string_lossy()));
+
+        for (i, version_entry) in versions.into_iter().enumerate() {
+            let version = version_entry.file_name().to_string_lossy().into_owned();
+
+            if indexed_versions.contains(&version) || policy.keep_last.is_some_and(|n| i < n) {
+                continue
+            }
+
+            if let Some(keep_since) = keep_since && fetched_at(manifest, &id, &version, &version_entry.path()).await? >= keep_since {
+                continue
+            }
+
+            garbage.push(Garbage {
+                bytes: dir_size(&version_entry.path()).await?,
+                path: version_entry.path(),
+                id: id.clone(),
+                version: Some(version),
+                archives: 1,
+            });
+        }
+    }
+
+    Ok((garbage, archives))
+}
+
+/// When a version was fetched, from the manifest or else the modification time of its directory.
+async fn fetched_at(manifest: &Manifest, id: &str, version: &str, path: &Path) -> anyhow::Result<SystemTime> {
+    let from_manifest = manifest.get(id, version)
+        .and_then(|entry| chrono::DateTime::parse_from_rfc3339(&entry.fetched_at).ok())
+        .map(SystemTime::from);
+
+    match from_manifest {
+        Some(fetched_at) => Ok(fetched_at),
+        None => Ok(tokio::fs::metadata(path).await?.modified()?)
+    }
+}
+
+async fn dir_size(path: &Path) -> anyhow::Result<u64> {
+    let mut size = 0;
+    let mut dirs = vec![path.to_path_buf()];
+
+    while let Some(dir) = dirs.pop() {
+        let mut entries = tokio::fs::read_dir(&dir).await?;
+
+        while let Some(entry) = entries.next_entry().await? {
+            let metadata = tokio::fs::symlink_metadata(entry.path()).await?;
+
+            if metadata.is_dir() {
+                dirs.push(entry.path());
+            } else {
+                size += metadata.len();
+            }
+        }
+    }
+
+    Ok(size)
+}
@@ -0,0 +1,221 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context};
+use clap::Parser;
+use tokio::fs::symlink;
+
+use crate::{downloader::{link_latest, part_path}, lock::OutputLock, mirror::latest_of_generation};
+
+const GENERATIONS_DIR: &str = "generations";
+const CURRENT: &str = "current";
+
+/// Files of the layout from before generations, superseded by the first promoted generation.
+const LEGACY_FILES: [&str; 4] = ["idx", "extensions.json", "manifest.json", "versions"];
+
+#[derive(Clone, Parser)]
+pub struct RollbackOpts {
+    #[arg(help="Generation to switch to [default: the one before the current]")]
+    pub generation: Option<String>,
+    #[arg(long, help="List the generations instead of switching")]
+    pub list: bool,
+}
+
+/// The directory holding the index and metadata of the current generation. Falls back to the
+/// output directory itself for mirrors that were created before generations.
+pub fn current_path(output: &str) -> PathBuf {
+    let current = PathBuf::from(format!("{output}/{CURRENT}"));
+
+    if current.exists() {
+        current
+    } else {
+        PathBuf::from(output)
+    }
+}
+
+/// The name of the current generation, if there is one.
+pub async fn current_generation(output: &str) -> Option<String> {
+    let target = tokio::fs::read_link(format!("{output}/{CURRENT}")).await.ok()?;
+
+    target.file_name().map(|name| name.to_string_lossy().into_owned())
+}
+
+/// All generations, oldest first.
+pub async fn list_generations(output: &str) -> anyhow::Result<Vec<String>> {
+    let mut generations = Vec::new();
+
+    let mut entries = match tokio::fs::read_dir(format!("{output}/{GENERATIONS_DIR}")).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(generations),
+        Err(e) => return Err(e.into())
+    };
+
+    while let Some(entry) = entries.next_entry().await? {
+        if entry.file_type().await?.is_dir() {
+            generations.push(entry.file_name().to_string_lossy().into_owned());
+        }
+    }
+
+    // Names are timestamps, so they sort chronologically.
+    generations.sort();
+
+    Ok(generations)
+}
+
+/// Turns a completely written directory into a new generation, makes it current and removes
+/// all but the `keep` generations before it.
+pub async fn promote(output: &str, new_path: &str, keep: usize) -> anyhow::Result<String> {
+    let generations_path = format!("{output}/{GENERATIONS_DIR}");
+
+    tokio::fs::create_dir_all(&generations_path).await?;
+
+    let mut name = chrono::Utc::now().format("%Y%m%dT%H%M%S%.3fZ").to_string();
+
+    while tokio::fs::try_exists(format!("{generations_path}/{name}")).await? {
+        name.push('_');
+    }
+
+    tokio::fs::rename(new_path, format!("{generations_path}/{name}")).await
+        .with_context(|| format!("moving {new_path} to generation {name}"))?;
+
+    switch_current(output, &name).await?;
+
+    remove_legacy_files(output).await?;
+
+    prune(output, keep).await?;
+
+    Ok(name)
+}
+
+/// Atomically points `current` at a generation.
+async fn switch_current(output: &str, name: &str) -> anyhow::Result<()> {
+    let current = PathBuf::from(format!("{output}/{CURRENT}"));
+    let tmp_current = part_path(&current);
+
+    _ = tokio::fs::remove_file(&tmp_current).await;
+
+    symlink(Path::new(GENERATIONS_DIR).join(name), &tmp_current).await?;
+
+    tokio::fs::rename(&tmp_current, &current).await?;
+
+    tokio::fs::File::open(output).await?
+        .sync_all().await?;
+
+    Ok(())
+}
+
+async fn prune(output: &str, keep: usize) -> anyhow::Result<()> {
+    let generations = list_generations(output).await?;
+    let current = current_generation(output).await;
+
+    let Some(current_pos) = generations.iter().position(|g| Some(g) == current.as_ref()) else {
+        return Ok(())
+    };
+
+    for name in &generations[..current_pos.saturating_sub(keep)] {
+        tokio::fs::remove_dir_all(format!("{output}/{GENERATIONS_DIR}/{name}")).await
+            .with_context(|| format!("removing generation {name}"))?;
+    }
+
+    Ok(())
+}
+
+async fn remove_legacy_files(output: &str) -> anyhow::Result<()> {
+    for name in LEGACY_FILES {
+        let path = format!("{output}/{name}");
+
+        let Ok(metadata) = tokio::fs::symlink_metadata(&path).await else {
+            continue
+        };
+
+        if metadata.is_dir() {
+            tokio::fs::remove_dir_all(&path).await?;
+        } else {
+            tokio::fs::remove_file(&path).await?;
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn rollback(opts: &RollbackOpts, mut output: &str) -> anyhow::Result<()> {
+    if let Some(path) = output.strip_suffix('/') {
+        output = path
+    }
+
+    let generations = list_generations(output).await?;
+    let current = current_generation(output).await;
+
+    if opts.list {
+        for name in &generations {
+            let marker = if Some(name) == current.as_ref() { "*" } else { " " };
+
+            println!("{marker} {name}");
+        }
+
+        return Ok(())
+    }
+
+    let _lock = OutputLock::acquire(output, std::time::Duration::ZERO).await?;
+
+    let target = match &opts.generation {
+        Some(name) => {
+            if !generations.contains(name) {
+                bail!("generation {name} does not exist")
+            }
+
+            name.clone()
+        },
+        None => {
+            let Some(current_pos) = generations.iter().position(|g| Some(g) == current.as_ref()) else {
+                bail!("there is no current generation")
+            };
+
+            let Some(previous) = current_pos.checked_sub(1).map(|i| generations[i].clone()) else {
+                bail!("there is no generation before {}", generations[current_pos])
+            };
+
+            previous
+        }
+    };
+
+    switch_current(output, &target).await?;
+
+    crate::log(format!("Switched from generation {} to {target}", current.unwrap_or_default()));
+
+    let relinked = link_latest_of_generation(output, &target).await
+        .with_context(|| "relinking latest archives")?;
+
+    crate::log(format!("Pointed {relinked} latest archive symlinks at the versions of generation {target}"));
+
+    Ok(())
+}
+
+/// Points the latest archive symlink of every extension at the version a mirror run of a
+/// generation links, as those links live outside the generations. Returns how many links were
+/// changed.
+async fn link_latest_of_generation(output: &str, name: &str) -> anyhow::Result<usize> {
+    let latest = latest_of_generation(Path::new(&format!("{output}/{GENERATIONS_DIR}/{name}"))).await?;
+
+    let mut relinked = 0;
+
+    for (id, version) in latest {
+        let primary_path = format!("{output}/extensions/{id}/{version}/archive.tar.gz");
+        let symlink_path = format!("{output}/extensions/{id}/archive.tar.gz");
+
+        let points_there = match (tokio::fs::canonicalize(&symlink_path).await, tokio::fs::canonicalize(&primary_path).await) {
+            (Ok(target), Ok(primary)) => target == primary,
+            (_, Err(_)) => {
+                crate::log(format!("WARN {id} {version} is not on disk, its latest archive symlink is left as it is"));
+                continue
+            },
+            (Err(_), Ok(_)) => false
+        };
+
+        if !points_there {
+            link_latest(&primary_path, &symlink_path).await?;
+            relinked += 1;
+        }
+    }
+
+    Ok(relinked)
+}
@@ -1,13 +1,38 @@
 use std::path::PathBuf;
 
-use tantivy::{schema::{Schema, SchemaBuilder, INDEXED, STORED, STRING, TEXT}, Index, IndexWriter, TantivyDocument};
+use serde::{Deserialize, Serialize};
+use tantivy::{schema::{Schema, SchemaBuilder, FAST, INDEXED, STORED, STRING, TEXT}, Index, IndexWriter, TantivyDocument};
 use tokio::fs::create_dir_all;
 
 use crate::{package_meta::ExtensionListData, progress::Progress};
 
+/// Stamped on every index this version creates. Bump it whenever `schema()` or the way documents
+/// are indexed changes, so that older indexes are rebuilt instead of served wrongly.
+pub const INDEX_SCHEMA_VERSION: u32 = 2;
+
+pub const ID_TEXT_FIELD: &str = "id_text";
+
+/// The upstream document as JSON, stored so that fields which are not indexed reach clients too.
+pub const SOURCE_FIELD: &str = "source_json";
+
+/// Kept in the commit payload of tantivy's meta.json, so it is written together with the index.
+#[derive(Serialize, Deserialize)]
+struct IndexStamp {
+    schema_version: u32,
+}
+
+/// The schema version an index was created with, `None` for indexes from before the stamp.
+pub fn schema_version(index: &Index) -> anyhow::Result<Option<u32>> {
+    let payload = index.load_metas()?.payload;
+
+    Ok(payload
+        .and_then(|payload| serde_json::from_str::<IndexStamp>(&payload).ok())
+        .map(|stamp| stamp.schema_version))
+}
 
 pub struct Indexer {
-    index: Index
+    index: Index,
+    payload: Option<String>,
 }
 
 impl Indexer {
@@ -20,8 +45,36 @@ impl Indexer {
 
         let index = Index::create_in_dir(format!("{output}/.tmp/idx"), schema.clone())?;
 
+        let payload = serde_json::to_string(&IndexStamp { schema_version: INDEX_SCHEMA_VERSION })?;
+
         Ok(Self {
-            index
+            index,
+            payload: Some(payload)
+        })
+    }
+
+    /// An index that lives in memory only, e.g. one rebuilt from stored documents on start.
+    pub fn in_ram() -> anyhow::Result<Self> {
+        let payload = serde_json::to_string(&IndexStamp { schema_version: INDEX_SCHEMA_VERSION })?;
+
+        Ok(Self {
+            index: Index::create_in_ram(schema()),
+            payload: Some(payload)
+        })
+    }
+
+    pub fn into_index(self) -> Index {
+        self.index
+    }
+
+    /// Adds to an index that already exists, e.g. the one being served. Its stamp is kept, as
+    /// adding documents does not change the schema.
+    pub fn from_index(index: Index) -> anyhow::Result<Self> {
+        let payload = index.load_metas()?.payload;
+
+        Ok(Self {
+            index,
+            payload
         })
     }
 
@@ -29,21 +82,41 @@ impl Indexer {
         let mut index_writer: IndexWriter = self.index.writer(15_000_000)?;
 
         let schema = self.index.schema();
+        // An index that is added to, e.g. by pull-through, may predate these fields.
+        let id_text_field = schema.get_field(ID_TEXT_FIELD).ok();
+        let source_field = schema.get_field(SOURCE_FIELD).ok();
 
         for mut package_meta in data.data {
+            let source = serde_json::to_string(&package_meta)?;
+
             if package_meta.get("wasm_api_version").map(|v| v.is_null()).unwrap_or(false) {
                 package_meta.remove("wasm_api_version");
             }
 
-            let doc = TantivyDocument::from_json_object(&schema, package_meta)?;
-                
+            let id = package_meta.get("id").and_then(|v| v.as_str()).map(str::to_string);
+
+            let mut doc = TantivyDocument::from_json_object(&schema, package_meta)?;
+
+            if let (Some(field), Some(id)) = (id_text_field, id) {
+                doc.add_text(field, id);
+            }
+
+            if let Some(field) = source_field {
+                doc.add_text(field, source);
+            }
 
             index_writer.add_document(doc)?;
 
             progress.files.inc_success(1);
         }
 
-        index_writer.commit()?;
+        let mut commit = index_writer.prepare_commit()?;
+
+        if let Some(payload) = &self.payload {
+            commit.set_payload(payload);
+        }
+
+        commit.commit()?;
 
         Ok(())
     }
@@ -52,17 +125,21 @@ impl Indexer {
 fn schema() -> Schema {
     let mut builder = SchemaBuilder::new();
 
-    builder.add_text_field("id", STORED | STRING);
+    // Fast, as `/extensions` ranks and deduplicates matches by them before loading documents.
+    builder.add_text_field("id", STORED | STRING | FAST);
     builder.add_text_field("name", STORED | TEXT);
-    builder.add_text_field("version", STORED | STRING);
-    builder.add_text_field("description", STORED | STRING);
+    builder.add_text_field("version", STORED | STRING | FAST);
+    builder.add_text_field("description", STORED | TEXT);
     builder.add_text_field("authors", STORED | TEXT);
     builder.add_text_field("repository", STORED | TEXT);
     builder.add_i64_field("schema_version", STORED | INDEXED);
     builder.add_text_field("wasm_api_version", STORED | TEXT);
     builder.add_text_field("provides", STORED | STRING);
     builder.add_text_field("published_at", STORED | STRING);
-    builder.add_u64_field("download_count", STORED | INDEXED);
+    builder.add_u64_field("download_count", STORED | INDEXED | FAST);
+    // The id split into words for searching, e.g. "tailwind-css" into "tailwind" and "css".
+    builder.add_text_field(ID_TEXT_FIELD, TEXT);
+    builder.add_text_field(SOURCE_FIELD, STORED);
 
     builder.build()
 }
\ No newline at end of file
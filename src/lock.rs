@@ -0,0 +1,100 @@
+use std::{fs::File, io::{Read, Seek, Write}, os::fd::AsRawFd, path::PathBuf, time::{Duration, Instant}};
+
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+use tokio::time::sleep;
+
+#[derive(Serialize, Deserialize)]
+struct LockInfo {
+    pid: u32,
+    host: String,
+    started_at: String,
+}
+
+/// Advisory lock on an output directory, held by runs that write to it. The lock is an flock on
+/// the lock file, which the kernel releases when the holder exits, so a crashed run never leaves
+/// a stale lock behind. The file itself stays, and only tells who holds the lock.
+pub struct OutputLock {
+    file: File,
+}
+
+impl OutputLock {
+    /// Takes the lock, waiting up to `timeout` for another run to release it.
+    pub async fn acquire(output: &str, timeout: Duration) -> anyhow::Result<Self> {
+        tokio::fs::create_dir_all(output).await?;
+
+        let path = PathBuf::from(format!("{output}/.lock"));
+        let started = Instant::now();
+
+        let mut file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .with_context(|| format!("opening {}", path.display()))?;
+
+        loop {
+            // SAFETY: the file descriptor stays open for as long as `file` lives.
+            let res = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+
+            if res == 0 {
+                let info = LockInfo {
+                    pid: std::process::id(),
+                    host: hostname(),
+                    started_at: crate::now(),
+                };
+
+                file.set_len(0)?;
+                file.rewind()?;
+                file.write_all(&serde_json::to_vec(&info)?)?;
+                file.sync_all()?;
+
+                return Ok(Self { file })
+            }
+
+            let e = std::io::Error::last_os_error();
+
+            if e.raw_os_error() != Some(libc::EWOULDBLOCK) {
+                return Err(e).with_context(|| format!("locking {}", path.display()))
+            }
+
+            if started.elapsed() >= timeout {
+                let mut buf = Vec::new();
+                file.rewind()?;
+                file.read_to_end(&mut buf)?;
+
+                match serde_json::from_slice::<LockInfo>(&buf) {
+                    Ok(holder) => bail!("{output} is locked by pid {} on {} since {}", holder.pid, holder.host, holder.started_at),
+                    Err(_) => bail!("{output} is locked, see {}", path.display())
+                }
+            }
+
+            sleep(Duration::from_secs(1)).await;
+        }
+    }
+}
+
+impl Drop for OutputLock {
+    fn drop(&mut self) {
+        // Cleared while still locked, so that nobody reads the details of a finished run. Closing
+        // the file releases the lock.
+        _ = self.file.set_len(0);
+    }
+}
+
+fn hostname() -> String {
+    let mut buf = [0_u8; 256];
+
+    // SAFETY: the buffer is valid for its whole length, and gethostname NUL terminates within it
+    // on success.
+    let res = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+
+    if res != 0 {
+        return String::from("unknown")
+    }
+
+    let len = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
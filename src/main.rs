@@ -9,9 +9,20 @@ mod progress;
 mod downloader;
 mod mirror;
 mod serve;
+mod certs;
 mod config;
+mod daemon;
 mod index;
+mod lock;
 mod ext_searcher;
+mod filter;
+mod gc;
+mod generation;
+mod manifest;
+mod pulled;
+mod rate_limit;
+mod reindex;
+mod verify;
 
 #[tokio::main()]
 async fn main() {
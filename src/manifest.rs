@@ -0,0 +1,106 @@
+use std::{collections::BTreeMap, path::Path, sync::{Arc, RwLock}};
+
+use serde::{Deserialize, Serialize};
+
+use crate::downloader::part_path;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub id: String,
+    pub version: String,
+    pub size: u64,
+    pub sha256: String,
+    pub fetched_at: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ArchiveId {
+    pub id: String,
+    pub version: String,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct ManifestFile {
+    archives: Vec<ManifestEntry>,
+}
+
+/// The size and SHA-256 of every mirrored archive, keyed by extension id and version.
+#[derive(Clone, Default)]
+pub struct Manifest {
+    entries: Arc<RwLock<BTreeMap<String, BTreeMap<String, ManifestEntry>>>>,
+}
+
+impl Manifest {
+    pub async fn load<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        let manifest = Self::default();
+
+        let buf = match tokio::fs::read(path).await {
+            Ok(buf) => buf,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(manifest),
+            Err(e) => return Err(e.into())
+        };
+
+        let file: ManifestFile = serde_json::from_slice(&buf)?;
+
+        for entry in file.archives {
+            manifest.insert(entry);
+        }
+
+        Ok(manifest)
+    }
+
+    /// Writes the manifest to a temporary sibling and renames it into place.
+    pub async fn save<P: AsRef<Path>>(&self, path: P) -> anyhow::Result<()> {
+        let file = ManifestFile {
+            archives: self.entries()
+        };
+
+        let tmp_path = part_path(&path);
+
+        tokio::fs::write(&tmp_path, serde_json::to_vec_pretty(&file)?).await?;
+        tokio::fs::rename(tmp_path, path).await?;
+
+        Ok(())
+    }
+
+    pub fn get(&self, id: &str, version: &str) -> Option<ManifestEntry> {
+        self.entries.read().expect("manifest lock poisoned")
+            .get(id)
+            .and_then(|versions| versions.get(version))
+            .cloned()
+    }
+
+    pub fn contains(&self, archive: &ArchiveId) -> bool {
+        self.get(&archive.id, &archive.version).is_some()
+    }
+
+    pub fn insert(&self, entry: ManifestEntry) {
+        self.entries.write().expect("manifest lock poisoned")
+            .entry(entry.id.clone())
+            .or_default()
+            .insert(entry.version.clone(), entry);
+    }
+
+    pub fn remove(&self, id: &str, version: &str) {
+        let mut entries = self.entries.write().expect("manifest lock poisoned");
+
+        if let Some(versions) = entries.get_mut(id) {
+            versions.remove(version);
+
+            if versions.is_empty() {
+                entries.remove(id);
+            }
+        }
+    }
+
+    pub fn remove_extension(&self, id: &str) {
+        self.entries.write().expect("manifest lock poisoned").remove(id);
+    }
+
+    pub fn entries(&self) -> Vec<ManifestEntry> {
+        self.entries.read().expect("manifest lock poisoned")
+            .values()
+            .flat_map(|versions| versions.values().cloned())
+            .collect()
+    }
+}
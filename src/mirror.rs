@@ -1,12 +1,14 @@
-use std::{os::unix::fs::MetadataExt, path::PathBuf};
+use std::{collections::{HashMap, HashSet}, os::unix::fs::MetadataExt, path::Path, str::FromStr, time::Duration};
 
 use anyhow::{bail, Context};
-use clap::{arg, Parser};
+use clap::Parser;
+use semver::Version;
+use serde_json::Map;
 use tokio::io::{AsyncReadExt, BufReader};
 
-use crate::{downloader::{Download, Downloader}, index::Indexer, package_meta::ExtensionListData, progress::spawn_updater};
+use crate::{downloader::{remove_partial_downloads, Download, Downloader, DownloaderOpts}, filter::{ExtensionFilter, Rule}, gc::{collect_garbage, GcPolicy}, generation::{current_path, promote}, index::Indexer, lock::OutputLock, manifest::{ArchiveId, Manifest}, package_meta::{cmp_versions, ExtensionListData}, progress::spawn_updater, pulled::PulledArchives};
 
-const MAX_SCHEMA_VERSION: i32 = 1_i32;
+const UPDATES_BATCH_SIZE: usize = 100;
 
 #[derive(Clone, Parser)]
 pub struct MirrorOpts {
@@ -15,11 +17,45 @@ pub struct MirrorOpts {
     pub api_url: String,
     #[arg(short, long, default_value_t=8u8)]
     pub dl_threads: u8,
+    #[arg(long, default_value="1", value_delimiter=',',
+        help="Extension schema versions to mirror the extension lists of, e.g. 1,2")]
+    pub schema_version: Vec<i32>,
+    #[arg(long, help="Also mirror the newest version of every extension within a wasm API range, \
+        written as MIN..MAX, e.g. 0.0.1..0.6.0. Can be repeated")]
+    pub wasm_api_range: Vec<WasmApiRange>,
+    #[arg(long, help="Mirror every published version of each extension, not only the latest")]
+    pub all_versions: bool,
+    #[arg(long, help="Mirror the latest N versions of each extension")]
+    pub max_versions: Option<usize>,
+    #[arg(long, help="Only mirror extensions matching this rule, e.g. \"id:python*\" or \"authors:*Zed*\". \
+        Fields are id, authors, repository and provides. Can be repeated")]
+    pub include: Vec<Rule>,
+    #[arg(long, help="Never mirror extensions matching this rule, e.g. \"provides:context-servers\". Can be repeated")]
+    pub exclude: Vec<Rule>,
+    #[arg(long, default_value_t=0u64,
+        help="Seconds to wait for another run holding the output directory lock")]
+    pub lock_timeout: u64,
+    #[arg(long, default_value_t=3usize,
+        help="Number of previous generations to keep for rollback")]
+    pub keep_generations: usize,
+    #[arg(long, help="Collect garbage after mirroring, see the gc command")]
+    pub gc: bool,
+    #[command(flatten)]
+    pub gc_policy: GcPolicy,
+    #[command(flatten)]
+    pub downloader: DownloaderOpts,
+}
+
+impl MirrorOpts {
+    fn mirrors_history(&self) -> bool {
+        self.all_versions || self.max_versions.is_some_and(|n| n > 1)
+    }
 }
 
 pub struct MirrorCtx {
     pub tmp_path: String,
     pub downloader: Downloader,
+    pub manifest: Manifest,
 }
 
 impl MirrorCtx {
@@ -29,19 +65,37 @@ impl MirrorCtx {
         }
 
         let tmp_path = format!("{output}/.tmp");
+
+        // Left over from an interrupted run, the next generation is built from scratch.
+        if tokio::fs::try_exists(&tmp_path).await? {
+            tokio::fs::remove_dir_all(&tmp_path).await?;
+        }
         
         tokio::fs::create_dir_all(&tmp_path).await?;
 
-        let downloader = Downloader::build(opts.dl_threads);
+        let removed = remove_partial_downloads(format!("{output}/extensions")).await
+            .with_context(|| "removing stale partial downloads")?;
+
+        if removed > 0 {
+            crate::log(format!("Removed {removed} stale partial downloads from a previous run"));
+        }
+
+        let manifest = Manifest::load(current_path(output).join("manifest.json")).await
+            .with_context(|| "loading manifest")?;
+
+        let downloader = Downloader::build(opts.dl_threads, &opts.downloader, manifest.clone())?;
 
         Ok(Self {
             tmp_path,
-            downloader
+            downloader,
+            manifest
         })
     }
 }
 
 pub async fn mirror(opts: &MirrorOpts, output: &str) -> anyhow::Result<()> {
+    let _lock = OutputLock::acquire(output, Duration::from_secs(opts.lock_timeout)).await?;
+
     crate::log("Mirroring started");
 
     let ctx = MirrorCtx::init(opts, output).await
@@ -49,49 +103,78 @@ pub async fn mirror(opts: &MirrorOpts, output: &str) -> anyhow::Result<()> {
 
     let progress = ctx.downloader.progress();
 
-    progress.set_total_steps(3);
+    progress.set_total_steps(if opts.mirrors_history() { 4 } else { 3 });
     progress.next_step("Downloading metadata").await;
 
     let ext_path = download_extension_list(&ctx, opts).await
         .with_context(|| "downloading extension list")?;
 
+    let mut ext_list = read_extension_list(&ext_path).await
+        .with_context(|| "reading extension list")?;
+
+    let filter = ExtensionFilter::new(opts.include.clone(), opts.exclude.clone());
+
+    if !filter.is_empty() {
+        filter_extension_list(&filter, &mut ext_list, &ext_path).await
+            .with_context(|| "filtering extension list")?;
+    }
+
+    let mut history = if opts.mirrors_history() {
+        progress.next_step("Downloading versions").await;
+
+        download_version_lists(&ctx, opts, &ext_list).await
+            .with_context(|| "downloading version lists")?
+    } else {
+        Vec::new()
+    };
+
+    let filtered_versions: usize = filter.retain(&mut history).values().map(Vec::len).sum();
+
+    if filtered_versions > 0 {
+        crate::log(format!("Filtered out {filtered_versions} older versions"));
+    }
+
     progress.next_step("Downloading extensions").await;
 
-    let ext_list = download_extensions(&ctx, opts, output, ext_path).await
+    download_extensions(&ctx, opts, output, &ext_list, &history).await
         .with_context(|| "downloading extensions")?;
 
     progress.next_step("Generating index").await;
 
-    generate_index(&ctx, output, ext_list).await
-        .with_context(|| "generating index")?;
+    ext_list.data.extend(history);
 
-    promote_tmp(&ctx, output).await
-        .with_context(|| "finishing up")?;
+    let pulled = PulledArchives::load(output).await?;
+    let (merged, filtered) = pulled.merge_into(&mut ext_list, &ctx.manifest, &filter)?;
 
-    crate::log("Mirroring completed");
+    for (reason, ids) in &filtered {
+        crate::log(format!("Filtered out {} pulled-through versions {reason}: {}", ids.len(), ids.join(", ")));
+    }
 
-    Ok(())
-}
+    if merged > 0 {
+        crate::log(format!("Kept {merged} pulled-through versions"));
+    }
 
-async fn promote_tmp(ctx: &MirrorCtx, output: &str) -> anyhow::Result<()> {
-    let new_meta = PathBuf::from(format!("{}/extensions.json", ctx.tmp_path));
+    generate_index(&ctx, output, ext_list).await
+        .with_context(|| "generating index")?;
 
-    let current_meta = PathBuf::from(format!("{output}/extensions.json"));
+    ctx.manifest.save(format!("{}/manifest.json", ctx.tmp_path)).await
+        .with_context(|| "writing manifest")?;
 
-    if tokio::fs::try_exists(&current_meta).await? {
-        tokio::fs::remove_file(&current_meta).await?;
-    }
+    let generation = promote(output, &ctx.tmp_path, opts.keep_generations).await
+        .with_context(|| "finishing up")?;
 
-    tokio::fs::rename(new_meta, current_meta).await?;
+    crate::log(format!("Promoted generation {generation}"));
 
-    let new_idx = PathBuf::from(format!("{}/idx", ctx.tmp_path));
-    let current_idx = PathBuf::from(format!("{output}/idx"));
+    // Filtered out ones included, which are collected like the other filtered out versions.
+    PulledArchives::forget(output, &pulled).await
+        .with_context(|| "dropping merged pull-through records")?;
 
-    if tokio::fs::try_exists(&current_idx).await? {
-        tokio::fs::remove_dir_all(&current_idx).await?;
+    if opts.gc {
+        collect_garbage(output, &opts.gc_policy, false, false).await
+            .with_context(|| "collecting garbage")?;
     }
-    
-    tokio::fs::rename(new_idx, current_idx).await?;
+
+    crate::log("Mirroring completed");
 
     Ok(())
 }
@@ -120,8 +203,8 @@ async fn generate_index(ctx: &MirrorCtx, output: &str, ext_list: ExtensionListDa
     Ok(())
 }
 
-async fn download_extensions(ctx: &MirrorCtx, opts: &MirrorOpts, output: &str, new_extension_file: String) -> anyhow::Result<ExtensionListData> {
-    let file = tokio::fs::File::open(new_extension_file).await?;
+pub async fn read_extension_list<P: AsRef<Path>>(path: P) -> anyhow::Result<ExtensionListData> {
+    let file = tokio::fs::File::open(path).await?;
 
     let size = file.metadata().await?.size();
 
@@ -131,7 +214,75 @@ async fn download_extensions(ctx: &MirrorCtx, opts: &MirrorOpts, output: &str, n
     
     _ = reader.read_to_end(&mut buf).await?;
 
-    let extension_list: ExtensionListData = serde_json::from_slice(&buf)?;
+    Ok(serde_json::from_slice(&buf)?)
+}
+
+/// Drops filtered out extensions from the list, also in the stored copy, and prints what was dropped.
+async fn filter_extension_list(filter: &ExtensionFilter, ext_list: &mut ExtensionListData, ext_path: &str) -> anyhow::Result<()> {
+    let filtered = filter.retain(&mut ext_list.data);
+
+    for (reason, ids) in &filtered {
+        crate::log(format!("Filtered out {} extensions {reason}: {}", ids.len(), ids.join(", ")));
+    }
+
+    crate::log(format!("Mirroring {} extensions, {} filtered out",
+        ext_list.data.len(), filtered.values().map(Vec::len).sum::<usize>()));
+
+    tokio::fs::write(ext_path, serde_json::to_vec(ext_list)?).await?;
+
+    Ok(())
+}
+
+/// The newest version of every extension in the list.
+pub fn latest_versions(ext_list: &ExtensionListData) -> anyhow::Result<HashMap<&str, &str>> {
+    let mut latest: HashMap<&str, &str> = HashMap::new();
+
+    for extension in &ext_list.data {
+        let (id, version) = id_and_version(extension)?;
+
+        let newest = latest.entry(id).or_insert(version);
+
+        if cmp_versions(version, newest).is_gt() {
+            *newest = version;
+        }
+    }
+
+    Ok(latest)
+}
+
+/// The versions a mirror run linked as the latest archives of a generation: the newest of its
+/// `extensions.json`, without the older and the pulled-through versions in its index.
+pub async fn latest_of_generation(generation_path: &Path) -> anyhow::Result<HashMap<String, String>> {
+    let ext_list = read_extension_list(generation_path.join("extensions.json")).await
+        .with_context(|| format!("reading {}/extensions.json", generation_path.display()))?;
+
+    Ok(latest_versions(&ext_list)?.into_iter()
+        .map(|(id, version)| (id.to_string(), version.to_string()))
+        .collect())
+}
+
+pub fn id_and_version(extension: &Map<String, serde_json::Value>) -> anyhow::Result<(&str, &str)> {
+    let Some(id) = extension.get("id").and_then(|v| v.as_str()) else {
+        bail!("document lacks string id field")
+    };
+
+    let Some(version) = extension.get("version").and_then(|v| v.as_str()) else {
+        bail!("document lacks string version field")
+    };
+
+    Ok((id, version))
+}
+
+/// Downloads the version list of every extension and returns the documents of the
+/// versions to mirror in addition to the latest ones.
+async fn download_version_lists(ctx: &MirrorCtx, opts: &MirrorOpts, ext_list: &ExtensionListData) -> anyhow::Result<Vec<Map<String, serde_json::Value>>> {
+    let versions_path = format!("{}/versions", ctx.tmp_path);
+
+    if tokio::fs::try_exists(&versions_path).await? {
+        tokio::fs::remove_dir_all(&versions_path).await?;
+    }
+
+    tokio::fs::create_dir_all(&versions_path).await?;
 
     let progress = ctx.downloader.progress();
 
@@ -139,21 +290,87 @@ async fn download_extensions(ctx: &MirrorCtx, opts: &MirrorOpts, output: &str, n
 
     let updater = spawn_updater(vec![(progress.clone(), pb.clone())]).await;
 
-    for extension in &extension_list.data {
-        let Some(id) = extension.get("id").and_then(|v| v.as_str()) else {
-            bail!("document lacks string id field")
-        };
+    let latest = latest_versions(ext_list)?;
+
+    for id in latest.keys() {
+        ctx.downloader.queue(Box::new(Download {
+            url: format!("{}/extensions/{id}", opts.api_url),
+            size: None,
+            primary_target_path: format!("{versions_path}/{id}.json"),
+            always_download: true,
+            symlink_path: None,
+            archive: None,
+            done: None,
+        })).await?;
+    }
 
-        let Some(version) = extension.get("version").and_then(|v| v.as_str()) else {
-            bail!("document lacks string version field")
+    progress.wait_for_completion(&pb).await;
+
+    updater.abort();
+
+    for failure in ctx.downloader.take_failures() {
+        crate::log(format!("WARN failed to download {}, mirroring only the latest version: {}", failure.url, failure.reason));
+    }
+
+    let listed = ext_list.data.iter()
+        .map(id_and_version)
+        .collect::<anyhow::Result<HashSet<_>>>()?;
+
+    let mut history = Vec::new();
+
+    for id in latest.keys() {
+        let Ok(versions) = read_extension_list(format!("{versions_path}/{id}.json")).await else {
+            continue
         };
 
+        let mut versions = versions.data;
+
+        versions.sort_by(|a, b| {
+            let version = |doc: &Map<String, serde_json::Value>| doc.get("version")
+                .and_then(|v| v.as_str())
+                .unwrap_or_default()
+                .to_owned();
+
+            cmp_versions(&version(b), &version(a))
+        });
+
+        if let Some(max_versions) = opts.max_versions {
+            versions.truncate(max_versions);
+        }
+
+        for doc in versions {
+            if !listed.contains(&id_and_version(&doc)?) {
+                history.push(doc);
+            }
+        }
+    }
+
+    Ok(history)
+}
+
+async fn download_extensions(ctx: &MirrorCtx, opts: &MirrorOpts, output: &str, ext_list: &ExtensionListData, history: &[Map<String, serde_json::Value>]) -> anyhow::Result<()> {
+    let progress = ctx.downloader.progress();
+
+    let pb = progress.create_download_progress_bar().await;
+
+    let updater = spawn_updater(vec![(progress.clone(), pb.clone())]).await;
+
+    // Only the newest listed version of an extension updates the archive.tar.gz symlink.
+    let latest = latest_versions(ext_list)?;
+
+    for extension in ext_list.data.iter().chain(history) {
+        let (id, version) = id_and_version(extension)?;
+
+        let is_latest = latest.get(id).is_some_and(|latest| *latest == version);
+
         let dl = Box::new(Download {
             url: format!("{}/extensions/{}/{}/download", opts.api_url, id, version),
             size: None,
             primary_target_path: format!("{output}/extensions/{}/{}/archive.tar.gz", id, version),
             always_download: false,
-            symlink_path: Some(format!("{output}/extensions/{}/archive.tar.gz", id))
+            symlink_path: is_latest.then(|| format!("{output}/extensions/{}/archive.tar.gz", id)),
+            archive: Some(ArchiveId { id: id.to_string(), version: version.to_string() }),
+            done: None,
         });
 
         ctx.downloader.queue(dl).await?;
@@ -163,32 +380,185 @@ async fn download_extensions(ctx: &MirrorCtx, opts: &MirrorOpts, output: &str, n
 
     updater.abort();
 
-    Ok(extension_list)
+    for failure in ctx.downloader.take_failures() {
+        crate::log(format!("WARN failed to download {}: {}", failure.url, failure.reason));
+    }
+
+    crate::log(format!("Extensions: {}", progress.files));
+
+    Ok(())
 }
 
 async fn download_extension_list(ctx: &MirrorCtx, opts: &MirrorOpts) -> anyhow::Result<String> {
+    let lists_path = format!("{}/lists", ctx.tmp_path);
+
+    if tokio::fs::try_exists(&lists_path).await? {
+        tokio::fs::remove_dir_all(&lists_path).await?;
+    }
+
+    tokio::fs::create_dir_all(&lists_path).await?;
+
+    let mut lists = Vec::new();
+
+    for schema_version in &opts.schema_version {
+        lists.push((
+            format!("{}/extensions?max_schema_version={schema_version}", &opts.api_url),
+            format!("{lists_path}/schema-{schema_version}.json")
+        ));
+    }
+
+    let mut merged = MergedExtensionList::default();
+
+    download_lists(ctx, &lists, &mut merged).await?;
+
+    if !opts.wasm_api_range.is_empty() {
+        // The list endpoint has no wasm API parameters, so the newest version of every extension
+        // within a wasm API range is asked for through the updates endpoint instead.
+        let ids = merged.ids();
+
+        lists.clear();
+
+        for schema_version in &opts.schema_version {
+            for range in &opts.wasm_api_range {
+                for (i, ids) in ids.chunks(UPDATES_BATCH_SIZE).enumerate() {
+                    lists.push((
+                        format!("{}/extensions/updates?ids={}&min_schema_version=0&max_schema_version={schema_version}&min_wasm_api_version={}&max_wasm_api_version={}",
+                            &opts.api_url, ids.join(","), range.min, range.max),
+                        format!("{lists_path}/schema-{schema_version}-wasm-{}-{}-{i}.json", range.min, range.max)
+                    ));
+                }
+            }
+        }
+
+        download_lists(ctx, &lists, &mut merged).await?;
+    }
+
+    let new_extensions_path = format!("{}/extensions.json", &ctx.tmp_path);
+
+    tokio::fs::write(&new_extensions_path, serde_json::to_vec(&ExtensionListData { data: merged.data })?).await?;
+
+    tokio::fs::remove_dir_all(&lists_path).await?;
+
+    Ok(new_extensions_path)
+}
+
+/// Downloads extension lists given as (url, path) pairs and merges them.
+async fn download_lists(ctx: &MirrorCtx, lists: &[(String, String)], merged: &mut MergedExtensionList) -> anyhow::Result<()> {
     let progress = ctx.downloader.progress();
 
     let pb = progress.create_download_no_size_progress_bar().await;
 
     let updater = spawn_updater(vec![(progress.clone(), pb.clone())]).await;
 
-    let new_extensions_path = format!("{}/extensions.json", &ctx.tmp_path);
-
-    let dl = Box::new(Download {
-        url: format!("{}/extensions?max_schema_version={MAX_SCHEMA_VERSION}", &opts.api_url),
-        size: None,
-        primary_target_path: new_extensions_path.clone(),
-        always_download: true,
-        symlink_path: None
-    });
+    for (url, path) in lists {
+        let dl = Box::new(Download {
+            url: url.clone(),
+            size: None,
+            primary_target_path: path.clone(),
+            always_download: true,
+            symlink_path: None,
+            archive: None,
+            done: None,
+        });
 
-    ctx.downloader.queue(dl).await
-        .with_context(|| "queuing download")?;
+        ctx.downloader.queue(dl).await
+            .with_context(|| "queuing download")?;
+    }
 
     progress.wait_for_completion(&pb).await;
 
     updater.abort();
 
-    Ok(new_extensions_path)
+    if let Some(failure) = ctx.downloader.take_failures().pop() {
+        bail!("{}: {}", failure.url, failure.reason)
+    }
+
+    for (_, path) in lists {
+        let list = read_extension_list(path).await
+            .with_context(|| format!("reading {path}"))?;
+
+        merged.extend(list)?;
+    }
+
+    Ok(())
+}
+
+/// Extension documents from several lists, unique by id and version.
+#[derive(Default)]
+struct MergedExtensionList {
+    seen: HashSet<(String, String)>,
+    data: Vec<Map<String, serde_json::Value>>,
+}
+
+impl MergedExtensionList {
+    fn extend(&mut self, list: ExtensionListData) -> anyhow::Result<()> {
+        for extension in list.data {
+            let (id, version) = id_and_version(&extension)?;
+
+            if self.seen.insert((id.to_string(), version.to_string())) {
+                self.data.push(extension);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn ids(&self) -> Vec<String> {
+        let mut ids: Vec<String> = self.seen.iter().map(|(id, _)| id.clone()).collect();
+
+        ids.sort();
+        ids.dedup();
+
+        ids
+    }
+}
+
+/// An inclusive range of wasm API versions, written as `MIN..MAX`.
+#[derive(Clone, Debug)]
+pub struct WasmApiRange {
+    pub min: Version,
+    pub max: Version,
+}
+
+impl FromStr for WasmApiRange {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((min, max)) = s.split_once("..") else {
+            bail!("expected MIN..MAX, e.g. 0.0.1..0.6.0")
+        };
+
+        let range = Self {
+            min: Version::parse(min.trim())?,
+            max: Version::parse(max.trim())?,
+        };
+
+        if range.min > range.max {
+            bail!("{} is greater than {}", range.min, range.max)
+        }
+
+        Ok(range)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wasm_api_range() {
+        let range: WasmApiRange = "0.0.1..0.6.0".parse().unwrap();
+        assert_eq!((range.min.to_string(), range.max.to_string()), ("0.0.1".to_string(), "0.6.0".to_string()));
+
+        let range: WasmApiRange = " 0.1.0 .. 0.1.0 ".parse().unwrap();
+        assert_eq!(range.min, range.max);
+    }
+
+    #[test]
+    fn wasm_api_range_invalid() {
+        assert!("0.1.0".parse::<WasmApiRange>().is_err());
+        assert!("0.1..0.2".parse::<WasmApiRange>().is_err());
+        assert!("..0.2.0".parse::<WasmApiRange>().is_err());
+        assert!("0.6.0..0.1.0".parse::<WasmApiRange>().is_err());
+    }
 }
@@ -1,12 +1,64 @@
+use std::cmp::Ordering;
+
+use anyhow::{bail, Context};
+use semver::Version;
 use serde::{Deserialize, Serialize};
 use serde_json::Map;
-use tantivy::schema::{document::{DeserializeError, DocumentDeserialize, DocumentDeserializer}, OwnedValue};
+use tantivy::schema::{NamedFieldDocument, OwnedValue};
+
+use crate::index::SOURCE_FIELD;
 
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize)]
 pub struct ExtensionListData {
     pub data: Vec<Map<String, serde_json::Value>>
 }
 
+/// Orders version strings by semver, falling back to a plain string comparison.
+pub fn cmp_versions(a: &str, b: &str) -> Ordering {
+    match (semver::Version::parse(a), semver::Version::parse(b)) {
+        (Ok(a), Ok(b)) => a.cmp(&b),
+        _ => a.cmp(b)
+    }
+}
+
+/// The wasm API versions a client can load, both bounds inclusive.
+#[derive(Clone, Debug, Default)]
+pub struct WasmApiBounds {
+    pub min: Option<Version>,
+    pub max: Option<Version>,
+}
+
+impl WasmApiBounds {
+    pub fn parse(min: Option<&str>, max: Option<&str>) -> anyhow::Result<Self> {
+        let parse = |v: Option<&str>| v.map(str::trim)
+            .filter(|v| !v.is_empty())
+            .map(|v| Version::parse(v).with_context(|| format!("invalid wasm API version {v}")))
+            .transpose();
+
+        Ok(Self {
+            min: parse(min)?,
+            max: parse(max)?,
+        })
+    }
+
+    /// Extensions without a wasm API version contain no wasm and are always allowed.
+    pub fn allows(&self, doc: &ExtensionMetadata) -> bool {
+        let Some(version) = &doc.wasm_api_version else {
+            return true
+        };
+
+        if self.min.is_none() && self.max.is_none() {
+            return true
+        }
+
+        let Ok(version) = Version::parse(version) else {
+            return false
+        };
+
+        self.min.as_ref().is_none_or(|min| &version >= min) && self.max.as_ref().is_none_or(|max| &version <= max)
+    }
+}
+
 #[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Default)]
 pub struct ExtensionMetadata {
     pub id: String,
@@ -20,110 +72,107 @@ pub struct ExtensionMetadata {
     pub schema_version: Option<i32>,
     pub wasm_api_version: Option<String>,
     pub provides: Vec<String>,
+    /// The whole upstream document, including fields that are not indexed.
+    #[serde(skip)]
+    pub source: Option<Map<String, serde_json::Value>>,
 }
 
+impl ExtensionMetadata {
+    /// Reads the stored fields of an index document by name, so that the order of the fields in
+    /// the schema does not matter. Fields this version does not know are skipped.
+    pub fn from_named_doc(doc: NamedFieldDocument) -> anyhow::Result<Self> {
+        let mut meta = ExtensionMetadata::default();
+
+        for (name, values) in doc.0 {
+            for value in values {
+                match (name.as_str(), value) {
+                    ("id", OwnedValue::Str(s)) => meta.id = s,
+                    ("name", OwnedValue::Str(s)) => meta.name = s,
+                    ("version", OwnedValue::Str(s)) => meta.version = s,
+                    ("description", OwnedValue::Str(s)) => meta.description = Some(s),
+                    ("authors", OwnedValue::Str(s)) => meta.authors.push(s),
+                    ("repository", OwnedValue::Str(s)) => meta.repository = s,
+                    ("schema_version", OwnedValue::I64(v)) => meta.schema_version = Some(v as i32),
+                    ("wasm_api_version", OwnedValue::Str(s)) => meta.wasm_api_version = Some(s),
+                    ("provides", OwnedValue::Str(s)) => meta.provides.push(s),
+                    ("published_at", OwnedValue::Str(s)) => meta.published_at = s,
+                    ("download_count", OwnedValue::U64(v)) => meta.download_count = v,
+                    (SOURCE_FIELD, OwnedValue::Str(s)) => meta.source = Some(serde_json::from_str(&s)
+                        .with_context(|| format!("{SOURCE_FIELD} is not a JSON object"))?),
+                    ("id" | "name" | "version" | "description" | "authors" | "repository" | "schema_version"
+                        | "wasm_api_version" | "provides" | "published_at" | "download_count" | SOURCE_FIELD, value) => {
+                        bail!("{name} has an unexpected value {value:?}")
+                    },
+                    _ => {}
+                }
+            }
+        }
 
+        Ok(meta)
+    }
 
-impl DocumentDeserialize for ExtensionMetadata {
-    fn deserialize<'de, D>(mut deserializer: D) -> Result<Self, DeserializeError>
-    where D: DocumentDeserializer<'de> {
-        let mut doc = ExtensionMetadata::default();
-
-        // TODO: Deserializing into OwnedValue is wasteful. The deserializer should be able to work
-        // on slices and referenced data.
-        while let Some((field, value)) = deserializer.next_field::<OwnedValue>()? {
-            match field.field_id() {
-                //id
-                0 => {
-                    let OwnedValue::Str(s) = value else {
-                        return Err(DeserializeError::Custom(String::from("id is not str")))
-                    };
-
-                    doc.id = s;
-                }
-                //name
-                1 => {
-                    let OwnedValue::Str(s) = value else {
-                        return Err(DeserializeError::Custom(String::from("name is not str")))
-                    };
+    /// The document as upstream published it, with the indexed fields filling in anything it
+    /// lacks, e.g. in an index written before the document was stored.
+    pub fn to_json(&self) -> Map<String, serde_json::Value> {
+        let mut json = match serde_json::to_value(self) {
+            Ok(serde_json::Value::Object(json)) => json,
+            _ => Map::new()
+        };
 
-                    doc.name = s;
-                }
-                //version
-                2 => {
-                    let OwnedValue::Str(s) = value else {
-                        return Err(DeserializeError::Custom(String::from("version is not str")))
-                    };
+        if let Some(source) = &self.source {
+            json.extend(source.clone());
+        }
 
-                    doc.version = s;
-                }
-                //description
-                3 => {
-                    let OwnedValue::Str(s) = value else {
-                        return Err(DeserializeError::Custom(String::from("description is not str")))
-                    };
+        json
+    }
+}
 
-                    doc.description = Some(s);
-                }
-                //authors
-                4 => {
-                    let OwnedValue::Str(s) = value else {
-                        return Err(DeserializeError::Custom(String::from("authors is not str")))
-                    };
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-                    doc.authors.push(s);
-                }
-                //repository
-                5 => {
-                    let OwnedValue::Str(s) = value else {
-                        return Err(DeserializeError::Custom(String::from("repository is not str")))
-                    };
+    fn with_wasm_api(version: Option<&str>) -> ExtensionMetadata {
+        ExtensionMetadata {
+            wasm_api_version: version.map(str::to_string),
+            ..Default::default()
+        }
+    }
 
-                    doc.repository = s;
-                }
-                //schema_version
-                6 => {
-                    let OwnedValue::I64(s) = value else {
-                        return Err(DeserializeError::Custom(String::from("schema_version is not i64")))
-                    };
+    #[test]
+    fn versions_by_semver() {
+        assert!(cmp_versions("0.10.0", "0.2.0").is_gt());
+        assert!(cmp_versions("1.0.0-beta.1", "1.0.0").is_lt());
+        assert!(cmp_versions("1.2.3", "1.2.3").is_eq());
+    }
 
-                    doc.schema_version = Some(s as i32);
-                }
-                //wasm_api_version
-                7 => {
-                    let OwnedValue::Str(s) = value else {
-                        return Err(DeserializeError::Custom(String::from("wasm_api_version is not str")))
-                    };
+    #[test]
+    fn versions_fall_back_to_strings() {
+        assert!(cmp_versions("2024-05", "2024-04").is_gt());
+        assert!(cmp_versions("v2", "1.0.0").is_gt());
+    }
 
-                    doc.wasm_api_version = Some(s);
-                }
-                //provides
-                8 => {
-                    let OwnedValue::Str(s) = value else {
-                        return Err(DeserializeError::Custom(String::from("provides is not str")))
-                    };
+    #[test]
+    fn wasm_api_bounds() {
+        let bounds = WasmApiBounds::parse(Some("0.1.0"), Some(" 0.2.0 ")).unwrap();
+
+        assert!(bounds.allows(&with_wasm_api(Some("0.1.0"))));
+        assert!(bounds.allows(&with_wasm_api(Some("0.2.0"))));
+        assert!(!bounds.allows(&with_wasm_api(Some("0.0.9"))));
+        assert!(!bounds.allows(&with_wasm_api(Some("0.3.0"))));
+        assert!(!bounds.allows(&with_wasm_api(Some("latest"))));
+        // No wasm at all.
+        assert!(bounds.allows(&with_wasm_api(None)));
+    }
 
-                    doc.provides.push(s);
-                }
-                //published_at
-                9 => {
-                    let OwnedValue::Str(s) = value else {
-                        return Err(DeserializeError::Custom(String::from("published_at is not str")))
-                    };
+    #[test]
+    fn wasm_api_bounds_open() {
+        let bounds = WasmApiBounds::parse(None, Some("")).unwrap();
+        assert!(bounds.allows(&with_wasm_api(Some("latest"))));
 
-                    doc.published_at = s;
-                }
-                //download_count
-                10 => {
-                    let OwnedValue::U64(s) = value else {
-                        return Err(DeserializeError::Custom(String::from("schema_version is not i64")))
-                    };
+        let bounds = WasmApiBounds::parse(Some("0.1.0"), None).unwrap();
+        assert!(bounds.allows(&with_wasm_api(Some("9.0.0"))));
+        assert!(!bounds.allows(&with_wasm_api(Some("0.0.1"))));
 
-                    doc.download_count = s;
-                }
-                x => return Err(DeserializeError::Custom(format!("unexpected field_id {x}")))
-            }
-        }
-        Ok(doc)
+        assert!(WasmApiBounds::parse(Some("one"), None).is_err());
     }
-}
\ No newline at end of file
+}
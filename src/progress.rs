@@ -1,6 +1,5 @@
-use std::{fmt::Display, sync::{atomic::{AtomicU64, AtomicU8, Ordering}, Arc}, time::Duration};
+use std::{fmt::Display, sync::{atomic::{AtomicU64, AtomicU8, Ordering}, Arc}, time::{Duration, Instant}};
 
-use compact_str::ToCompactString;
 use console::{style, pad_str};
 use indicatif::{ProgressBar, ProgressStyle, ProgressFinish, HumanBytes};
 use tokio::{sync::Mutex, task::JoinHandle, time::sleep};
@@ -12,7 +11,9 @@ pub struct Progress {
     pub files: ProgressPart,
     pub bytes: ProgressPart,
     pub total_bytes: Arc<AtomicU64>,
-    total_steps: Arc<AtomicU8>
+    total_steps: Arc<AtomicU8>,
+    step_started: Arc<std::sync::Mutex<Option<Instant>>>,
+    rate_limit: Arc<AtomicU64>,
 }
 
 impl Progress {
@@ -23,7 +24,9 @@ impl Progress {
             files: ProgressPart::new(),
             bytes: ProgressPart::new(),
             total_bytes: Arc::new(AtomicU64::new(0)),
-            total_steps: Arc::new(AtomicU8::new(4))
+            total_steps: Arc::new(AtomicU8::new(4)),
+            step_started: Default::default(),
+            rate_limit: Arc::new(AtomicU64::new(0)),
         }
     }
 
@@ -85,7 +88,32 @@ impl Progress {
             progress_bar.set_position(self.files.total() - self.files.remaining());
         }
 
-        progress_bar.set_message(HumanBytes(self.bytes.success()).to_compact_string());
+        progress_bar.set_message(self.bytes_message());
+    }
+
+    /// Downloaded bytes, and once anything has been downloaded the average throughput
+    /// of the step against the bandwidth limit.
+    fn bytes_message(&self) -> String {
+        let bytes = self.bytes.success();
+
+        let elapsed = self.step_started.lock().expect("progress lock poisoned")
+            .map(|started| started.elapsed().as_secs_f64())
+            .unwrap_or_default();
+
+        if bytes == 0 || elapsed <= 0.0 {
+            return HumanBytes(bytes).to_string()
+        }
+
+        let throughput = HumanBytes((bytes as f64 / elapsed) as u64);
+
+        match self.rate_limit.load(Ordering::SeqCst) {
+            0 => format!("{} @ {throughput}/s", HumanBytes(bytes)),
+            limit => format!("{} @ {throughput}/s of {}/s", HumanBytes(bytes), HumanBytes(limit))
+        }
+    }
+
+    pub fn set_rate_limit(&self, bytes_per_sec: Option<u64>) {
+        self.rate_limit.store(bytes_per_sec.unwrap_or_default(), Ordering::SeqCst);
     }
 
     pub fn set_total_steps(&self, num_steps: u8) {
@@ -98,6 +126,8 @@ impl Progress {
         self.bytes.reset();
         self.files.reset();
 
+        *self.step_started.lock().expect("progress lock poisoned") = Some(Instant::now());
+
         self.step.fetch_add(1, Ordering::SeqCst);
     }
     
@@ -169,11 +199,9 @@ impl ProgressPart {
         self.skipped.fetch_add(count, Ordering::SeqCst);
     }
 
-    /*
     pub fn inc_failed(&self, count: u64) {
         self.failed.fetch_add(count, Ordering::SeqCst);
     }
-     */
 
     pub fn total(&self) -> u64 {
         self.total.load(Ordering::SeqCst)
@@ -0,0 +1,147 @@
+use std::{collections::{BTreeMap, HashSet}, time::Duration};
+
+use anyhow::Context;
+use serde_json::{Map, Value};
+
+use crate::{downloader::part_path, filter::ExtensionFilter, lock::OutputLock, manifest::{Manifest, ManifestEntry}, mirror::{id_and_version, read_extension_list}, package_meta::ExtensionListData};
+
+/// Where pull-through keeps what it fetched. Generations are not changed once promoted, so the
+/// archives are recorded here until mirror or reindex merge them into the next one.
+const PULL_THROUGH_DIR: &str = "pull-through";
+
+/// How long recording waits for a run that is updating the records at the same time.
+const RECORDS_LOCK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The archives fetched by pull-through: their manifest entries and upstream documents. Until the
+/// next mirror or reindex merges them into its generation, pulled archives are only recorded
+/// here. The records are dropped once a promoted generation has them, so that they are then
+/// collected like any other version that the generations no longer list.
+pub struct PulledArchives {
+    pub manifest: Manifest,
+    pub list: ExtensionListData,
+}
+
+impl PulledArchives {
+    pub async fn load(output: &str) -> anyhow::Result<Self> {
+        let manifest = Manifest::load(manifest_path(output)).await
+            .with_context(|| "loading pull-through manifest")?;
+
+        let list = if tokio::fs::try_exists(list_path(output)).await? {
+            read_extension_list(list_path(output)).await
+                .with_context(|| "reading pull-through extension list")?
+        } else {
+            ExtensionListData { data: Vec::new() }
+        };
+
+        Ok(Self { manifest, list })
+    }
+
+    async fn save(&self, output: &str) -> anyhow::Result<()> {
+        tokio::fs::create_dir_all(records_path(output)).await?;
+
+        let tmp_path = part_path(list_path(output));
+
+        tokio::fs::write(&tmp_path, serde_json::to_vec(&self.list)?).await?;
+        tokio::fs::rename(&tmp_path, list_path(output)).await?;
+
+        self.manifest.save(manifest_path(output)).await
+    }
+
+    pub fn contains(&self, id: &str, version: &str) -> bool {
+        self.list.data.iter()
+            .any(|doc| id_and_version(doc).is_ok_and(|doc| doc == (id, version)))
+    }
+
+    /// Adds the record of a pulled archive to the stored ones.
+    pub async fn record(output: &str, doc: Map<String, Value>, entry: ManifestEntry) -> anyhow::Result<()> {
+        let _lock = OutputLock::acquire(&records_path(output), RECORDS_LOCK_TIMEOUT).await?;
+
+        let mut archives = Self::load(output).await?;
+
+        if !archives.contains(&entry.id, &entry.version) {
+            archives.list.data.push(doc);
+        }
+
+        archives.manifest.insert(entry);
+
+        archives.save(output).await
+    }
+
+    /// Drops the stored records of the archives in `merged`, once a promoted generation has them.
+    /// Archives pulled since `merged` was loaded stay recorded.
+    pub async fn forget(output: &str, merged: &PulledArchives) -> anyhow::Result<()> {
+        if merged.list.data.is_empty() {
+            return Ok(())
+        }
+
+        let _lock = OutputLock::acquire(&records_path(output), RECORDS_LOCK_TIMEOUT).await?;
+
+        let mut archives = Self::load(output).await?;
+
+        archives.list.data.retain(|doc| id_and_version(doc)
+            .is_ok_and(|(id, version)| !merged.contains(id, version)));
+
+        for doc in &merged.list.data {
+            let (id, version) = id_and_version(doc)?;
+
+            if !archives.contains(id, version) {
+                archives.manifest.remove(id, version);
+            }
+        }
+
+        archives.save(output).await
+    }
+
+    /// Adds the documents of pulled archives that `list` lacks and `filter` lets through, and their
+    /// entries that `manifest` lacks. Returns the number of added documents, and the ids of the
+    /// filtered out ones grouped by reason.
+    pub fn merge_into(&self, list: &mut ExtensionListData, manifest: &Manifest, filter: &ExtensionFilter) -> anyhow::Result<(usize, BTreeMap<String, Vec<String>>)> {
+        let mut seen = list.data.iter()
+            .map(|doc| id_and_version(doc).map(|(id, version)| (id.to_string(), version.to_string())))
+            .collect::<anyhow::Result<HashSet<_>>>()?;
+
+        let mut docs = self.list.data.clone();
+        let filtered = filter.retain(&mut docs);
+
+        let mut merged = 0;
+
+        for doc in docs {
+            let (id, version) = id_and_version(&doc)?;
+
+            // Only archives that were fetched completely have an entry.
+            let Some(entry) = self.manifest.get(id, version) else {
+                continue
+            };
+
+            if manifest.get(id, version).is_none() {
+                manifest.insert(entry);
+            }
+
+            if seen.insert((id.to_string(), version.to_string())) {
+                list.data.push(doc);
+                merged += 1;
+            }
+        }
+
+        Ok((merged, filtered))
+    }
+}
+
+/// Where pull-through downloads archives to before they are moved into `extensions/`. Kept apart,
+/// so that a mirror run cleaning up after interrupted downloads never sees a running one.
+pub fn staging_path(output: &str) -> String {
+    format!("{output}/{PULL_THROUGH_DIR}/staging")
+}
+
+/// Also where the lock on the records is taken.
+fn records_path(output: &str) -> String {
+    format!("{output}/{PULL_THROUGH_DIR}")
+}
+
+fn manifest_path(output: &str) -> String {
+    format!("{output}/{PULL_THROUGH_DIR}/manifest.json")
+}
+
+fn list_path(output: &str) -> String {
+    format!("{output}/{PULL_THROUGH_DIR}/extensions.json")
+}
@@ -0,0 +1,118 @@
+use std::{sync::Arc, time::{Duration, Instant}};
+
+use anyhow::bail;
+use tokio::{sync::Mutex, time::sleep};
+
+/// A token bucket of bytes shared by all of its clones. A bucket holds at most one
+/// second worth of tokens; a request larger than what is available puts the bucket in
+/// debt and waits until it is paid off.
+#[derive(Clone)]
+pub struct RateLimiter {
+    bytes_per_sec: u64,
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        Self {
+            bytes_per_sec,
+            bucket: Arc::new(Mutex::new(Bucket {
+                tokens: bytes_per_sec as f64,
+                refilled_at: Instant::now(),
+            }))
+        }
+    }
+
+    pub async fn acquire(&self, bytes: u64) {
+        let wait = {
+            let mut bucket = self.bucket.lock().await;
+
+            let now = Instant::now();
+            let refill = now.duration_since(bucket.refilled_at).as_secs_f64() * self.bytes_per_sec as f64;
+
+            bucket.tokens = (bucket.tokens + refill).min(self.bytes_per_sec as f64) - bytes as f64;
+            bucket.refilled_at = now;
+
+            if bucket.tokens >= 0.0 {
+                return
+            }
+
+            Duration::from_secs_f64(-bucket.tokens / self.bytes_per_sec as f64)
+        };
+
+        sleep(wait).await
+    }
+}
+
+/// The limiters a single download is subject to.
+#[derive(Clone, Default)]
+pub struct Throttle {
+    pub global: Option<RateLimiter>,
+    pub connection: Option<RateLimiter>,
+}
+
+impl Throttle {
+    pub async fn consume(&self, bytes: u64) {
+        if let Some(limiter) = &self.connection {
+            limiter.acquire(bytes).await;
+        }
+
+        if let Some(limiter) = &self.global {
+            limiter.acquire(bytes).await;
+        }
+    }
+}
+
+/// Parses a rate like `500K`, `2M` or `1.5G` (bytes per second, 1024 based) into bytes per second.
+pub fn parse_byte_rate(s: &str) -> anyhow::Result<u64> {
+    let s = s.trim().trim_end_matches("/s");
+
+    let (number, multiplier) = match s.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&s[..i], 1024_f64),
+        Some((i, 'm' | 'M')) => (&s[..i], 1024_f64.powi(2)),
+        Some((i, 'g' | 'G')) => (&s[..i], 1024_f64.powi(3)),
+        _ => (s, 1_f64)
+    };
+
+    let rate = (number.trim().parse::<f64>()? * multiplier) as u64;
+
+    if rate == 0 {
+        bail!("rate must be greater than zero")
+    }
+
+    Ok(rate)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn byte_rate_units() {
+        assert_eq!(parse_byte_rate("1000").unwrap(), 1000);
+        assert_eq!(parse_byte_rate("500K").unwrap(), 500 * 1024);
+        assert_eq!(parse_byte_rate("2m").unwrap(), 2 * 1024 * 1024);
+        assert_eq!(parse_byte_rate("1G").unwrap(), 1024 * 1024 * 1024);
+        assert_eq!(parse_byte_rate("1.5M").unwrap(), 1536 * 1024);
+    }
+
+    #[test]
+    fn byte_rate_with_suffix_and_spaces() {
+        assert_eq!(parse_byte_rate(" 2M/s ").unwrap(), 2 * 1024 * 1024);
+        assert_eq!(parse_byte_rate("2 K").unwrap(), 2048);
+    }
+
+    #[test]
+    fn byte_rate_invalid() {
+        assert!(parse_byte_rate("").is_err());
+        assert!(parse_byte_rate("K").is_err());
+        assert!(parse_byte_rate("fast").is_err());
+        assert!(parse_byte_rate("0").is_err());
+        assert!(parse_byte_rate("-1M").is_err());
+    }
+}
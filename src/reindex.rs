@@ -0,0 +1,185 @@
+use std::{collections::HashSet, path::Path, time::Duration};
+
+use anyhow::{bail, Context};
+use clap::{Parser, ValueEnum};
+use tantivy::Index;
+
+use crate::{ext_searcher::ExtSearcher, filter::ExtensionFilter, generation::{current_path, promote}, index::{schema_version, Indexer, INDEX_SCHEMA_VERSION}, lock::OutputLock, manifest::Manifest, mirror::{id_and_version, read_extension_list}, progress::Progress, pulled::PulledArchives};
+
+/// Metadata of a generation that is carried over into the rebuilt one.
+const METADATA_FILES: [&str; 2] = ["extensions.json", "manifest.json"];
+
+#[derive(Clone, Parser)]
+pub struct ReindexOpts {
+    #[arg(long, help="Rebuild even if the index already has the current schema version")]
+    pub force: bool,
+    #[arg(long, default_value_t=3usize,
+        help="Number of previous generations to keep for rollback")]
+    pub keep_generations: usize,
+}
+
+/// What `serve` does with an index written with another schema version.
+#[derive(Clone, Copy, ValueEnum)]
+pub enum SchemaMismatch {
+    /// Rebuild the index from the stored metadata before serving it.
+    Rebuild,
+    /// Serve it anyway and log a warning.
+    Warn,
+    /// Refuse to serve it.
+    Refuse,
+}
+
+pub fn describe_schema_version(version: Option<u32>) -> String {
+    version.map(|v| v.to_string()).unwrap_or_else(|| String::from("none"))
+}
+
+/// Rebuilds the index of the current generation from its stored metadata, without touching the
+/// network.
+pub async fn reindex(opts: &ReindexOpts, mut output: &str) -> anyhow::Result<()> {
+    if let Some(path) = output.strip_suffix('/') {
+        output = path
+    }
+
+    let _lock = OutputLock::acquire(output, Duration::ZERO).await?;
+
+    let found = Index::open_in_dir(current_path(output).join("idx")).ok()
+        .and_then(|index| schema_version(&index).ok().flatten());
+
+    if !opts.force && found == Some(INDEX_SCHEMA_VERSION) {
+        crate::log(format!("Index already has schema version {INDEX_SCHEMA_VERSION}, use --force to rebuild it anyway"));
+        return Ok(())
+    }
+
+    rebuild_index(output, opts.keep_generations).await?;
+
+    Ok(())
+}
+
+/// Writes a new generation with the metadata of the current one and a freshly built index, and
+/// promotes it. The caller has to hold the output lock.
+///
+/// The index gets every extension of `extensions.json`, the older versions from `versions/*.json`
+/// that are in the manifest, whatever else the manifest lists that the old index still knows
+/// about, and the pulled archives.
+pub async fn rebuild_index(output: &str, keep_generations: usize) -> anyhow::Result<String> {
+    let source = tokio::fs::canonicalize(current_path(output)).await?;
+    let tmp_path = format!("{output}/.tmp");
+
+    if !tokio::fs::try_exists(source.join("extensions.json")).await? {
+        bail!("{} has no extensions.json to rebuild the index from, run mirror instead", source.display())
+    }
+
+    if tokio::fs::try_exists(&tmp_path).await? {
+        tokio::fs::remove_dir_all(&tmp_path).await?;
+    }
+
+    tokio::fs::create_dir_all(&tmp_path).await?;
+
+    for name in METADATA_FILES {
+        if tokio::fs::try_exists(source.join(name)).await? {
+            tokio::fs::copy(source.join(name), format!("{tmp_path}/{name}")).await
+                .with_context(|| format!("copying {name}"))?;
+        }
+    }
+
+    let versions_path = source.join("versions");
+    let version_lists = copy_dir(&versions_path, Path::new(&format!("{tmp_path}/versions"))).await
+        .with_context(|| "copying versions")?;
+
+    let mut ext_list = read_extension_list(source.join("extensions.json")).await
+        .with_context(|| "reading extensions.json")?;
+
+    let manifest = Manifest::load(source.join("manifest.json")).await
+        .with_context(|| "loading manifest")?;
+
+    let mut seen = ext_list.data.iter()
+        .map(|doc| id_and_version(doc).map(|(id, version)| (id.to_string(), version.to_string())))
+        .collect::<anyhow::Result<HashSet<_>>>()?;
+
+    let mut older_versions = 0;
+
+    for name in version_lists.iter().filter(|name| name.ends_with(".json")) {
+        let list = read_extension_list(versions_path.join(name)).await
+            .with_context(|| format!("reading versions/{name}"))?;
+
+        for doc in list.data {
+            let (id, version) = id_and_version(&doc)?;
+
+            if manifest.get(id, version).is_some() && seen.insert((id.to_string(), version.to_string())) {
+                ext_list.data.push(doc);
+                older_versions += 1;
+            }
+        }
+    }
+
+    let mut carried_over = 0;
+
+    let old_docs = Index::open_in_dir(source.join("idx"))
+        .map_err(anyhow::Error::from)
+        .and_then(ExtSearcher::init)
+        .and_then(|searcher| searcher.get_all_extensions());
+
+    match old_docs {
+        Ok(docs) => for doc in docs {
+            if manifest.get(&doc.id, &doc.version).is_some() && seen.insert((doc.id.clone(), doc.version.clone())) {
+                ext_list.data.push(doc.to_json());
+                carried_over += 1;
+            }
+        },
+        Err(e) => crate::log(format!("WARN could not read the old index, only the stored metadata is indexed: {e:#}"))
+    }
+
+    // The filters of the mirror runs are not known here, the next one drops what they exclude.
+    let pulled_archives = PulledArchives::load(output).await?;
+    let (pulled, _) = pulled_archives.merge_into(&mut ext_list, &manifest, &ExtensionFilter::default())?;
+
+    // The copied manifest lacks the entries of the pulled archives.
+    manifest.save(format!("{tmp_path}/manifest.json")).await
+        .with_context(|| "writing manifest")?;
+
+    let documents = ext_list.data.len();
+
+    let indexer = Indexer::init(output).await?;
+
+    tokio::task::spawn_blocking(move || indexer.index(ext_list, Progress::default())).await?
+        .with_context(|| "indexing documents")?;
+
+    let generation = promote(output, &tmp_path, keep_generations).await
+        .with_context(|| "finishing up")?;
+
+    PulledArchives::forget(output, &pulled_archives).await
+        .with_context(|| "dropping merged pull-through records")?;
+
+    crate::log(format!("Reindexed {documents} documents ({older_versions} older versions, {carried_over} carried over from the old index, {pulled} pulled through) into generation {generation}"));
+
+    Ok(generation)
+}
+
+/// Copies the files of a directory, if it exists, and returns their names.
+async fn copy_dir(from: &Path, to: &Path) -> anyhow::Result<Vec<String>> {
+    let mut entries = match tokio::fs::read_dir(from).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into())
+    };
+
+    tokio::fs::create_dir_all(to).await?;
+
+    let mut names = Vec::new();
+
+    while let Some(entry) = entries.next_entry().await? {
+        if !entry.file_type().await?.is_file() {
+            continue
+        }
+
+        let name = entry.file_name().to_string_lossy().into_owned();
+
+        tokio::fs::copy(entry.path(), to.join(&name)).await?;
+
+        names.push(name);
+    }
+
+    names.sort();
+
+    Ok(names)
+}
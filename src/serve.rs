@@ -1,53 +1,320 @@
-use std::{net::{IpAddr, Ipv4Addr, SocketAddr}, sync::Arc};
+use std::{future::{Future, IntoFuture}, net::{IpAddr, Ipv4Addr, SocketAddr}, path::PathBuf, pin::Pin, sync::{Arc, Mutex, RwLock}, time::Duration};
 
+use anyhow::{bail, Context};
 use axum::Router;
 use clap::Parser;
+use futures_util::{future::try_join_all, FutureExt};
 use tantivy::Index;
-use tokio::{net::TcpListener, signal};
+use tokio::signal;
 
-use crate::ext_searcher::ExtSearcher;
+use crate::{downloader::DownloaderOpts, ext_searcher::ExtSearcher, generation::current_path, index::{schema_version, INDEX_SCHEMA_VERSION}, lock::OutputLock, manifest::Manifest, reindex::{describe_schema_version, rebuild_index, SchemaMismatch}, serve::{listen::{parse_mode, systemd_listeners, BoundListener, ListenAddr, SD_HTTPS_NAME}, pull_through::{PullThrough, PullThroughOpts, PulledIndex}, tls::{TlsListener, TlsOpts}}};
 
 pub mod extensions;
+pub mod file;
+pub mod listen;
+pub mod pull_through;
+pub mod tls;
 
 #[derive(Clone, Parser)]
 pub struct ServeOpts {
     #[arg(long, short, help="Web server port", default_value = "8070")]
-    pub port: u16
+    pub port: u16,
+    #[arg(long, help="Address to listen on: IP:PORT, [IPv6]:PORT, *:PORT for IPv6 and IPv4 or unix:/path. \
+        Can be repeated [default: 0.0.0.0:PORT]")]
+    pub listen: Vec<ListenAddr>,
+    #[arg(long, default_value="660", value_parser=parse_mode, help="Permissions of unix sockets, in octal")]
+    pub unix_socket_mode: u32,
+    #[arg(long, help="Seconds between checks for a newly promoted generation, 0 to only reload on SIGHUP", default_value = "10")]
+    pub reload_interval: u64,
+    #[arg(long, value_enum, default_value_t=SchemaMismatch::Rebuild,
+        help="What to do with an index written with another schema version")]
+    pub on_schema_mismatch: SchemaMismatch,
+    #[command(flatten)]
+    pub pull_through: PullThroughOpts,
+    #[command(flatten)]
+    pub tls: TlsOpts,
+}
+
+// The daemon takes the downloader options from its mirror options, so they are only added to
+// the serve command here.
+#[derive(Clone, Parser)]
+pub struct ServeCommandOpts {
+    #[command(flatten)]
+    pub serve: ServeOpts,
+    #[command(flatten)]
+    pub downloader: DownloaderOpts,
+}
+
+/// The index and manifest of one generation, with the pulled archives searched along. Requests
+/// hold on to the snapshot they started with, so swapping in a new one does not disturb them.
+pub struct Snapshot {
+    pub path: PathBuf,
+    pub searcher: ExtSearcher,
+    pub manifest: Manifest,
+    pub pulled: PulledIndex,
+    pub schema_version: Option<u32>,
+}
+
+impl Snapshot {
+    async fn open(output: &str) -> anyhow::Result<Self> {
+        // Resolved once, so a generation promoted later does not change files under an open index.
+        let path = tokio::fs::canonicalize(current_path(output)).await?;
+
+        let index = Index::open_in_dir(path.join("idx"))?;
+
+        let schema_version = schema_version(&index)?;
+
+        let pulled = PulledIndex::load(output).await
+            .with_context(|| "loading pulled archives")?;
+
+        let searcher = ExtSearcher::init(index)?.overlaid(&pulled.searcher);
+
+        let manifest = Manifest::load(path.join("manifest.json")).await?;
+
+        Ok(Self {
+            path,
+            searcher,
+            manifest,
+            pulled,
+            schema_version
+        })
+    }
+
+    /// Describes how the schema version of the index differs from the one of this build, if it does.
+    fn schema_mismatch(&self) -> Option<String> {
+        if self.schema_version == Some(INDEX_SCHEMA_VERSION) {
+            return None
+        }
+
+        Some(format!("generation {} has index schema version {}, this zedmirs uses {INDEX_SCHEMA_VERSION}",
+            self.generation(), describe_schema_version(self.schema_version)))
+    }
+
+    fn generation(&self) -> String {
+        self.path.file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    }
 }
 
 #[derive(Clone)]
 pub struct AppState {
-    searcher: ExtSearcher,
+    snapshot: Arc<RwLock<Arc<Snapshot>>>,
+    pull_through: Option<Arc<PullThrough>>,
+    /// Held while a snapshot is opened and swapped in, and while a pulled archive is recorded.
+    swapping: Arc<tokio::sync::Mutex<()>>,
     output: Arc<str>,
+    on_schema_mismatch: SchemaMismatch,
+    /// A generation that was not swapped in because of its schema version, so that it is not
+    /// opened and reported again on every check.
+    rejected: Arc<Mutex<Option<PathBuf>>>,
 }
 
 impl AppState {
-    pub fn init(output: &str) -> anyhow::Result<Self> {
-        let index = Index::open_in_dir(format!("{output}/idx"))?;
+    pub async fn init(output: &str, opts: &ServeOpts, downloader_opts: &DownloaderOpts) -> anyhow::Result<Self> {
+        let mut snapshot = Snapshot::open(output).await?;
+
+        if let Some(mismatch) = snapshot.schema_mismatch() {
+            match opts.on_schema_mismatch {
+                SchemaMismatch::Refuse => bail!("{mismatch}, run the reindex command"),
+                SchemaMismatch::Warn => crate::log(format!("WARN {mismatch}, serving it anyway")),
+                SchemaMismatch::Rebuild => {
+                    crate::log(format!("Rebuilding the index, {mismatch}"));
 
-        let searcher = ExtSearcher::init(index)?;
+                    // Generations are only pruned by mirror runs, whose --keep-generations is not known here.
+                    let _lock = OutputLock::acquire(output, Duration::ZERO).await?;
+
+                    rebuild_index(output, usize::MAX).await
+                        .with_context(|| "rebuilding index")?;
+
+                    snapshot = Snapshot::open(output).await?;
+                }
+            }
+        }
+
+        let pull_through = if opts.pull_through.pull_through {
+            Some(Arc::new(PullThrough::new(&opts.pull_through, downloader_opts)?))
+        } else {
+            None
+        };
 
         let output: Arc<str> = Arc::from(output);
 
         Ok(Self {
-            searcher,
-            output
+            snapshot: Arc::new(RwLock::new(Arc::new(snapshot))),
+            pull_through,
+            swapping: Arc::default(),
+            output,
+            on_schema_mismatch: opts.on_schema_mismatch,
+            rejected: Arc::default(),
         })
     }
+
+    pub fn snapshot(&self) -> Arc<Snapshot> {
+        self.snapshot.read().expect("snapshot lock poisoned").clone()
+    }
+
+    /// Opens the current generation and swaps it in if it differs from the one being served, or
+    /// unconditionally when `force` is set. A generation rejected for its schema version is only
+    /// opened again when forced.
+    pub async fn reload(&self, force: bool) -> anyhow::Result<()> {
+        let _swapping = self.swapping.lock().await;
+
+        let old = self.snapshot();
+
+        if !force {
+            let path = tokio::fs::canonicalize(current_path(&self.output)).await?;
+
+            if path == old.path || self.rejected.lock().expect("rejected lock poisoned").as_ref() == Some(&path) {
+                return Ok(())
+            }
+        }
+
+        let new = Snapshot::open(&self.output).await?;
+
+        if let Some(mismatch) = new.schema_mismatch() {
+            match self.on_schema_mismatch {
+                SchemaMismatch::Warn => crate::log(format!("WARN {mismatch}, serving it anyway")),
+                // Not rebuilt here, where a mirror run may still be going. The generation being
+                // served stays until the new one is reindexed.
+                _ => {
+                    *self.rejected.lock().expect("rejected lock poisoned") = Some(new.path.clone());
+
+                    bail!("{mismatch}, run the reindex command")
+                }
+            }
+        }
+
+        crate::log(format!("Reloaded index, generation {} -> {}", old.generation(), new.generation()));
+
+        *self.rejected.lock().expect("rejected lock poisoned") = None;
+        *self.snapshot.write().expect("snapshot lock poisoned") = Arc::new(new);
+
+        Ok(())
+    }
+}
+
+/// Reloads the index when a new generation is promoted, checking every `interval` seconds and on
+/// SIGHUP.
+fn spawn_reloader(state: AppState, interval: u64) -> anyhow::Result<()> {
+    #[cfg(unix)]
+    let mut hangup = signal::unix::signal(signal::unix::SignalKind::hangup())?;
+
+    tokio::spawn(async move {
+        let mut ticker = (interval > 0).then(|| tokio::time::interval(Duration::from_secs(interval)));
+
+        loop {
+            let tick = async {
+                match &mut ticker {
+                    Some(ticker) => _ = ticker.tick().await,
+                    None => std::future::pending().await
+                }
+            };
+
+            #[cfg(unix)]
+            let force = tokio::select! {
+                _ = tick => false,
+                _ = hangup.recv() => true,
+            };
+
+            #[cfg(not(unix))]
+            let force = {
+                tick.await;
+                false
+            };
+
+            if let Err(e) = state.reload(force).await {
+                crate::log(format!("WARN failed to reload index: {e:#}"));
+            }
+        }
+    });
+
+    Ok(())
 }
 
-pub async fn serve(opts: &ServeOpts, output: &str) -> anyhow::Result<()> {
-    let state = AppState::init(output)?;
+pub async fn serve(opts: &ServeCommandOpts, output: &str) -> anyhow::Result<()> {
+    let state = AppState::init(output, &opts.serve, &opts.downloader).await?;
 
-    let app = Router::new()
+    let app = router(state.clone());
+
+    listen(&opts.serve, state, app).await
+}
+
+pub fn router(state: AppState) -> Router {
+    Router::new()
         .merge(extensions::get_routes(state.clone()))
-        .with_state(state);
+        .with_state(state)
+}
 
-    let listener = TcpListener::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), opts.port)).await?;
+/// Serves `app` until shut down, reloading the index of `state` along the way.
+pub async fn listen(opts: &ServeOpts, state: AppState, app: Router) -> anyhow::Result<()> {
+    spawn_reloader(state, opts.reload_interval)?;
+
+    let acceptor = match (&opts.tls.tls_cert, &opts.tls.tls_key) {
+        (Some(cert_path), Some(key_path)) => Some(tls::acceptor(cert_path, key_path)
+            .with_context(|| "setting up HTTPS")?),
+        _ => None
+    };
+
+    let mut listeners = Vec::new();
+
+    if let Some(activated) = systemd_listeners().with_context(|| "taking over sockets from systemd")? {
+        for (name, listener) in activated {
+            if name == SD_HTTPS_NAME {
+                let Some(acceptor) = &acceptor else {
+                    bail!("systemd passed an {SD_HTTPS_NAME} socket, but --tls-cert is not set")
+                };
+
+                listeners.push((format!("systemd socket {name}"), listener, Some(acceptor.clone())));
+            } else {
+                listeners.push((format!("systemd socket {name}"), listener, None));
+            }
+        }
+    } else {
+        let any = |port| vec![ListenAddr::Tcp(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port))];
+
+        if !opts.tls.tls_only {
+            let addrs = if opts.listen.is_empty() { any(opts.port) } else { opts.listen.clone() };
+
+            for addr in addrs {
+                let listener = addr.bind(opts.unix_socket_mode).await
+                    .with_context(|| format!("listening on {addr}"))?;
+
+                listeners.push((format!("http://{addr}"), listener, None));
+            }
+        }
+
+        if let Some(acceptor) = &acceptor {
+            let addrs = if opts.tls.tls_listen.is_empty() { any(opts.tls.tls_port) } else { opts.tls.tls_listen.clone() };
+
+            for addr in addrs {
+                let listener = addr.bind(opts.unix_socket_mode).await
+                    .with_context(|| format!("listening on {addr}"))?;
+
+                listeners.push((format!("https://{addr}"), listener, Some(acceptor.clone())));
+            }
+        }
+    }
+
+    let shutdown = shutdown_signal().shared();
+
+    let mut servers: Vec<Pin<Box<dyn Future<Output = std::io::Result<()>> + Send>>> = Vec::new();
+
+    for (name, listener, acceptor) in listeners {
+        crate::log(format!("Listening on {name}"));
+
+        let app = app.clone();
+        let shutdown = shutdown.clone();
+
+        servers.push(match (listener, acceptor) {
+            (BoundListener::Tcp(l), None) => Box::pin(axum::serve(l, app).with_graceful_shutdown(shutdown).into_future()),
+            (BoundListener::Unix(l), None) => Box::pin(axum::serve(l, app).with_graceful_shutdown(shutdown).into_future()),
+            (BoundListener::Tcp(l), Some(acceptor)) => Box::pin(axum::serve(TlsListener::new(l, acceptor)?, app).with_graceful_shutdown(shutdown).into_future()),
+            (BoundListener::Unix(l), Some(acceptor)) => Box::pin(axum::serve(TlsListener::new(l, acceptor)?, app).with_graceful_shutdown(shutdown).into_future()),
+        });
+    }
 
-    axum::serve(listener, app)
-        .with_graceful_shutdown(shutdown_signal())
-        .await?;
+    try_join_all(servers).await?;
 
     Ok(())
 }
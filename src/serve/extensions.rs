@@ -1,9 +1,10 @@
-use axum::{body::Body, extract::{Path, Query, State}, response::IntoResponse, routing::get, Json, Router};
-use reqwest::{header, StatusCode};
+use axum::{extract::{Path, Query, State}, http::Method, response::{IntoResponse, Response}, routing::get, Json, Router};
+use base64::{prelude::BASE64_STANDARD, Engine};
+use reqwest::{header::{self, HeaderMap, HeaderName, HeaderValue}, StatusCode};
 use serde::{Deserialize, Serialize};
-use tokio_util::io::ReaderStream;
+use serde_json::Map;
 
-use crate::{package_meta::ExtensionMetadata, serve::AppState};
+use crate::{manifest::ManifestEntry, package_meta::{ExtensionMetadata, WasmApiBounds}, serve::{file::{serve_file, CACHE_IMMUTABLE, CACHE_REVALIDATE}, AppState}};
 
 pub fn get_routes(state: AppState) -> Router<AppState> {
     Router::new()
@@ -23,7 +24,10 @@ pub fn get_routes(state: AppState) -> Router<AppState> {
 
 #[derive(Serialize)]
 pub struct GetExtensionsResult {
-    data: Vec<ExtensionMetadata>
+    data: Vec<Map<String, serde_json::Value>>,
+    /// The number of matches before `limit` and `offset` were applied.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total: Option<usize>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -33,10 +37,13 @@ pub struct GetExtensionsParams {
     pub provides: Option<String>,
     #[serde(default)]
     pub max_schema_version: i32,
+    pub limit: Option<usize>,
+    #[serde(default)]
+    pub offset: usize,
 }
 
 async fn get_extensions(State(state): State<AppState>, Query(params): Query<GetExtensionsParams>) -> Result<Json<GetExtensionsResult>, StatusCode> {
-    let data = match state.searcher.get_extensions(&params) {
+    let (data, total) = match state.snapshot().searcher.get_extensions(&params) {
         Ok(v) => v,
         Err(e) => {
             crate::log(format!("WARN {e}"));
@@ -44,7 +51,7 @@ async fn get_extensions(State(state): State<AppState>, Query(params): Query<GetE
         }
     };
     
-    Ok(Json(GetExtensionsResult { data }))
+    Ok(Json(GetExtensionsResult { data: data.iter().map(ExtensionMetadata::to_json).collect(), total: Some(total) }))
 }
 
 #[derive(Debug, Deserialize)]
@@ -52,12 +59,14 @@ pub struct GetExtensionUpdatesParams {
     pub ids: String,
     pub min_schema_version: i32,
     pub max_schema_version: i32,
-    //pub min_wasm_api_version: String,
-    //pub max_wasm_api_version: String,
-}//
+    pub min_wasm_api_version: Option<String>,
+    pub max_wasm_api_version: Option<String>,
+}
 
 async fn get_extension_updates(State(state): State<AppState>, Query(params): Query<GetExtensionUpdatesParams>) -> Result<Json<GetExtensionsResult>, StatusCode> {
-    let data = match state.searcher.get_extension_updates(&params) {
+    let wasm_api = wasm_api_bounds(params.min_wasm_api_version.as_deref(), params.max_wasm_api_version.as_deref())?;
+
+    let data = match state.snapshot().searcher.get_extension_updates(&params, &wasm_api) {
         Ok(v) => v,
         Err(e) => {
             crate::log(format!("WARN {e}"));
@@ -65,7 +74,7 @@ async fn get_extension_updates(State(state): State<AppState>, Query(params): Que
         }
     };
     
-    Ok(Json(GetExtensionsResult { data }))
+    Ok(Json(GetExtensionsResult { data: data.iter().map(ExtensionMetadata::to_json).collect(), total: None }))
 }
 
 #[derive(Debug, Deserialize)]
@@ -74,7 +83,7 @@ pub struct GetExtensionVersionsParams {
 }
 
 async fn get_extension_versions(State(state): State<AppState>, Path(params): Path<GetExtensionVersionsParams>) -> Result<Json<GetExtensionsResult>, StatusCode> {
-    let data = match state.searcher.get_extension_versions(&params) {
+    let data = match state.snapshot().searcher.get_extension_versions(&params) {
         Ok(v) => v,
         Err(e) => {
             crate::log(format!("WARN {e}"));
@@ -82,7 +91,7 @@ async fn get_extension_versions(State(state): State<AppState>, Path(params): Pat
         }
     };
     
-    Ok(Json(GetExtensionsResult { data }))
+    Ok(Json(GetExtensionsResult { data: data.iter().map(ExtensionMetadata::to_json).collect(), total: None }))
 }
 
 #[derive(Debug, Deserialize)]
@@ -90,31 +99,122 @@ struct DownloadLatestExtensionPathParams {
     extension_id: String,
 }
 
-/* 
 #[derive(Debug, Deserialize)]
 struct DownloadLatestExtensionQueryParams {
     min_schema_version: Option<i32>,
     max_schema_version: Option<i32>,
     min_wasm_api_version: Option<String>,
     max_wasm_api_version: Option<String>,
-}*/
+}
+
+impl DownloadLatestExtensionQueryParams {
+    fn is_constrained(&self) -> bool {
+        self.min_schema_version.is_some() || self.max_schema_version.is_some()
+            || self.min_wasm_api_version.is_some() || self.max_wasm_api_version.is_some()
+    }
+
+    fn allows_schema_version(&self, doc: &ExtensionMetadata) -> bool {
+        let schema_version = doc.schema_version.unwrap_or_default();
+
+        self.min_schema_version.is_none_or(|min| schema_version >= min)
+            && self.max_schema_version.is_none_or(|max| schema_version <= max)
+    }
+}
+
+async fn download_latest_extension(State(state): State<AppState>, Path(params): Path<DownloadLatestExtensionPathParams>, Query(query): Query<DownloadLatestExtensionQueryParams>, method: Method, request: HeaderMap) -> Result<Response, StatusCode> {
+    if query.is_constrained() {
+        let wasm_api = wasm_api_bounds(query.min_wasm_api_version.as_deref(), query.max_wasm_api_version.as_deref())?;
+
+        let versions = match state.snapshot().searcher.get_extension_versions(&GetExtensionVersionsParams { extension_id: params.extension_id.clone() }) {
+            Ok(v) => v,
+            Err(e) => {
+                crate::log(format!("WARN {e}"));
+                return Err(StatusCode::INTERNAL_SERVER_ERROR)
+            }
+        };
+
+        // An extension that is not indexed at all is left to the symlink and pull-through below.
+        if !versions.is_empty() {
+            // Newest first, so the first archive on disk that fits is the one to serve. With
+            // pull-through, the newest one that fits is fetched instead of serving an older one.
+            for doc in versions.iter().filter(|doc| query.allows_schema_version(doc) && wasm_api.allows(doc)) {
+                let file_path = format!("{}/extensions/{}/{}/archive.tar.gz", state.output, doc.id, doc.version);
+
+                if let Ok(file) = tokio::fs::File::open(file_path).await {
+                    return Ok(serve_archive(&state, file, &doc.id, Some(&doc.version), &method, &request, CACHE_REVALIDATE).await)
+                }
+
+                if state.pull_through.is_some() {
+                    return pull_through(&state, &doc.id, Some(&doc.version)).await
+                }
+            }
+
+            return Err(StatusCode::NOT_FOUND)
+        }
+    }
 
-async fn download_latest_extension(State(state): State<AppState>, Path(params): Path<DownloadLatestExtensionPathParams>) -> Result<impl IntoResponse, StatusCode> {
     let file_path = format!("{}/extensions/{}/archive.tar.gz", state.output, params.extension_id);
 
-    let Ok(file) = tokio::fs::File::open(file_path).await else {
+    let Ok(file) = tokio::fs::File::open(&file_path).await else {
+        return pull_through(&state, &params.extension_id, None).await
+    };
+
+    // The latest archive is a symlink to {version}/archive.tar.gz.
+    let version = tokio::fs::read_link(&file_path).await.ok()
+        .and_then(|target| target.parent().map(|v| v.to_string_lossy().into_owned()));
+
+    // The symlink moves to every new version, so caches have to check back.
+    Ok(serve_archive(&state, file, &params.extension_id, version.as_deref(), &method, &request, CACHE_REVALIDATE).await)
+}
+
+fn wasm_api_bounds(min: Option<&str>, max: Option<&str>) -> Result<WasmApiBounds, StatusCode> {
+    WasmApiBounds::parse(min, max).map_err(|_| StatusCode::BAD_REQUEST)
+}
+
+/// Serves a mirrored or pulled archive, with its hash from the manifest when the version is known.
+async fn serve_archive(state: &AppState, file: tokio::fs::File, id: &str, version: Option<&str>, method: &Method, request: &HeaderMap, cache_control: &'static str) -> Response {
+    let snapshot = state.snapshot();
+    let entry = version.and_then(|version| snapshot.manifest.get(id, version)
+        .or_else(|| snapshot.pulled.manifest.get(id, version)));
+
+    let mut header = archive_headers();
+
+    if let Some(entry) = &entry {
+        add_digest_header(&mut header, entry);
+    }
+
+    serve_file(file, method, request, header, entry.as_ref().map(|e| e.sha256.as_str()), cache_control).await
+}
+
+fn archive_headers() -> HeaderMap {
+    let mut header = HeaderMap::new();
+
+    header.insert(header::CONTENT_TYPE, HeaderValue::from_static("application/octet-stream"));
+    header.insert(header::CONTENT_DISPOSITION, HeaderValue::from_static("attachment; filename=archive.tar.gz"));
+
+    header
+}
+
+/// Answers a request for an archive that is not mirrored, from the upstream if pull-through is on.
+async fn pull_through(state: &AppState, id: &str, version: Option<&str>) -> Result<Response, StatusCode> {
+    let Some(pull_through) = &state.pull_through else {
         return Err(StatusCode::NOT_FOUND)
     };
 
-    let header = [
-        (header::CONTENT_TYPE, "application/octet-stream".to_owned()),
-        (header::CONTENT_DISPOSITION, "attachment; filename=archive.tar.gz".to_owned())
-    ];
+    let body = pull_through.fetch(state, id, version).await?;
+
+    Ok((archive_headers(), body).into_response())
+}
 
-    let stream = ReaderStream::new(file);
-    let body = Body::from_stream(stream);
+/// Adds the SHA-256 recorded at mirror time as an RFC 3230 `Digest` header.
+fn add_digest_header(header: &mut HeaderMap, entry: &ManifestEntry) {
+    let Ok(sha256) = hex::decode(&entry.sha256) else {
+        return
+    };
 
-    Ok((header, body))
+    if let Ok(value) = HeaderValue::from_str(&format!("sha-256={}", BASE64_STANDARD.encode(sha256))) {
+        header.insert(HeaderName::from_static("digest"), value);
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -123,20 +223,12 @@ struct DownloadExtensionParams {
     version: String,
 }
 
-async fn download_extension(State(state): State<AppState>, Path(params): Path<DownloadExtensionParams>) -> Result<impl IntoResponse, StatusCode> {
+async fn download_extension(State(state): State<AppState>, Path(params): Path<DownloadExtensionParams>, method: Method, request: HeaderMap) -> Result<Response, StatusCode> {
     let file_path = format!("{}/extensions/{}/{}/archive.tar.gz", state.output, params.extension_id, params.version);
 
     let Ok(file) = tokio::fs::File::open(file_path).await else {
-        return Err(StatusCode::NOT_FOUND)
+        return pull_through(&state, &params.extension_id, Some(&params.version)).await
     };
     
-    let header = [
-        (header::CONTENT_TYPE, "application/octet-stream".to_owned()),
-        (header::CONTENT_DISPOSITION, "attachment; filename=archive.tar.gz".to_owned())
-    ];
-
-    let stream = ReaderStream::new(file);
-    let body = Body::from_stream(stream);
-
-    Ok((header, body))
-}
\ No newline at end of file
+    Ok(serve_archive(&state, file, &params.extension_id, Some(&params.version), &method, &request, CACHE_IMMUTABLE).await)
+}
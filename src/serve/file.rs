@@ -0,0 +1,402 @@
+use std::{collections::VecDeque, io::SeekFrom, ops::Range};
+
+use axum::{body::Body, http::{header, HeaderMap, HeaderValue, Method, StatusCode}, response::{IntoResponse, Response}};
+use chrono::{DateTime, SubsecRound, Utc};
+use tokio::{fs::File, io::{AsyncReadExt, AsyncSeekExt}};
+
+/// Requests for more ranges than this get the whole file, so that a single request cannot be
+/// made arbitrarily expensive.
+const MAX_RANGES: usize = 16;
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Cache-Control of a file that never changes once written, like an archive of a given version.
+pub const CACHE_IMMUTABLE: &str = "public, max-age=31536000, immutable";
+
+/// Cache-Control of a file that changes over time, like the latest archive. Caches revalidate it
+/// on every request, which the ETag makes cheap.
+pub const CACHE_REVALIDATE: &str = "public, no-cache";
+
+enum Ranges {
+    Full,
+    Satisfiable(Vec<Range<u64>>),
+    Unsatisfiable,
+}
+
+/// Part of a response body: either literal bytes or a section of the file.
+enum Segment {
+    Bytes(Vec<u8>),
+    File { start: u64, len: u64 },
+}
+
+impl Segment {
+    fn len(&self) -> u64 {
+        match self {
+            Segment::Bytes(bytes) => bytes.len() as u64,
+            Segment::File { len, .. } => *len,
+        }
+    }
+}
+
+/// Answers a GET or HEAD request for `file` with validators, conditional requests and byte ranges.
+/// `headers` go out with every response that has a body. `sha256` is the hash recorded in the
+/// manifest and becomes the ETag; without it the ETag is made from size and modification time.
+pub async fn serve_file(file: File, method: &Method, request: &HeaderMap, mut headers: HeaderMap, sha256: Option<&str>, cache_control: &'static str) -> Response {
+    let metadata = match file.metadata().await {
+        Ok(metadata) => metadata,
+        Err(e) => {
+            crate::log(format!("WARN {e}"));
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    };
+
+    let len = metadata.len();
+    // HTTP dates have a resolution of seconds.
+    let modified = metadata.modified().ok().map(|m| DateTime::<Utc>::from(m).trunc_subsecs(0));
+
+    let etag = match (sha256, modified) {
+        (Some(sha256), _) => format!("\"{sha256}\""),
+        (None, Some(modified)) => format!("\"{len:x}-{:x}\"", modified.timestamp()),
+        (None, None) => format!("\"{len:x}\""),
+    };
+
+    let mut validators = HeaderMap::new();
+
+    if let Ok(value) = HeaderValue::from_str(&etag) {
+        validators.insert(header::ETAG, value);
+    }
+
+    if let Some(modified) = modified && let Ok(value) = HeaderValue::from_str(&http_date(modified)) {
+        validators.insert(header::LAST_MODIFIED, value);
+    }
+
+    validators.insert(header::CACHE_CONTROL, HeaderValue::from_static(cache_control));
+
+    if not_modified(request, &etag, modified) {
+        return (StatusCode::NOT_MODIFIED, validators).into_response()
+    }
+
+    headers.extend(validators);
+    headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+
+    let ranges = match request.get(header::RANGE) {
+        Some(range) if if_range_matches(request, &etag, modified) => parse_ranges(range, len),
+        _ => Ranges::Full
+    };
+
+    let (status, segments) = match ranges {
+        Ranges::Full => (StatusCode::OK, vec![Segment::File { start: 0, len }]),
+        Ranges::Unsatisfiable => {
+            headers.insert(header::CONTENT_RANGE, content_range(None, len));
+            headers.insert(header::CONTENT_LENGTH, HeaderValue::from(0));
+
+            return (StatusCode::RANGE_NOT_SATISFIABLE, headers).into_response()
+        },
+        Ranges::Satisfiable(ranges) if ranges.len() == 1 => {
+            let range = &ranges[0];
+
+            headers.insert(header::CONTENT_RANGE, content_range(Some(range), len));
+
+            (StatusCode::PARTIAL_CONTENT, vec![Segment::File { start: range.start, len: range.end - range.start }])
+        },
+        Ranges::Satisfiable(ranges) => {
+            let boundary = format!("zedmirs-{:016x}{:016x}", fastrand::u64(..), fastrand::u64(..));
+
+            let part_type = headers.get(header::CONTENT_TYPE).cloned()
+                .unwrap_or(HeaderValue::from_static("application/octet-stream"));
+
+            let mut segments = Vec::new();
+
+            for range in &ranges {
+                let mut part_header = format!("\r\n--{boundary}\r\n").into_bytes();
+                part_header.extend_from_slice(b"Content-Type: ");
+                part_header.extend_from_slice(part_type.as_bytes());
+                part_header.extend_from_slice(b"\r\nContent-Range: ");
+                part_header.extend_from_slice(content_range(Some(range), len).as_bytes());
+                part_header.extend_from_slice(b"\r\n\r\n");
+
+                segments.push(Segment::Bytes(part_header));
+                segments.push(Segment::File { start: range.start, len: range.end - range.start });
+            }
+
+            segments.push(Segment::Bytes(format!("\r\n--{boundary}--\r\n").into_bytes()));
+
+            if let Ok(value) = HeaderValue::from_str(&format!("multipart/byteranges; boundary={boundary}")) {
+                headers.insert(header::CONTENT_TYPE, value);
+            }
+
+            (StatusCode::PARTIAL_CONTENT, segments)
+        }
+    };
+
+    headers.insert(header::CONTENT_LENGTH, HeaderValue::from(segments.iter().map(Segment::len).sum::<u64>()));
+
+    if method == Method::HEAD {
+        return (status, headers).into_response()
+    }
+
+    let reader = SegmentReader {
+        file,
+        segments: segments.into(),
+        position: 0,
+        buf: vec![0_u8; CHUNK_SIZE],
+    };
+
+    let stream = futures_util::stream::unfold(reader, |mut reader| async move {
+        reader.next_chunk().await.map(|chunk| (chunk, reader))
+    });
+
+    (status, headers, Body::from_stream(stream)).into_response()
+}
+
+fn http_date(date: DateTime<Utc>) -> String {
+    date.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+fn parse_http_date(value: &HeaderValue) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc2822(value.to_str().ok()?).ok().map(|date| date.with_timezone(&Utc))
+}
+
+/// Whether the client's copy is still current. `If-None-Match` takes precedence over
+/// `If-Modified-Since`, and compares weakly.
+fn not_modified(request: &HeaderMap, etag: &str, modified: Option<DateTime<Utc>>) -> bool {
+    if let Some(if_none_match) = request.get(header::IF_NONE_MATCH) {
+        let Ok(if_none_match) = if_none_match.to_str() else {
+            return false
+        };
+
+        return if_none_match.split(',')
+            .map(str::trim)
+            .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+    }
+
+    match (request.get(header::IF_MODIFIED_SINCE).and_then(parse_http_date), modified) {
+        (Some(since), Some(modified)) => modified <= since,
+        _ => false
+    }
+}
+
+/// Whether a range request applies to this file. With an `If-Range` that does not match, the
+/// client gets the whole file instead.
+fn if_range_matches(request: &HeaderMap, etag: &str, modified: Option<DateTime<Utc>>) -> bool {
+    let Some(if_range) = request.get(header::IF_RANGE) else {
+        return true
+    };
+
+    match if_range.to_str() {
+        // Entity tags are compared strongly, so a weak one never matches.
+        Ok(tag) if tag.starts_with('"') || tag.starts_with("W/") => tag == etag,
+        Ok(_) => parse_http_date(if_range).is_some_and(|date| Some(date) == modified),
+        Err(_) => false
+    }
+}
+
+/// Parses a `Range` header. A header that is malformed, uses another unit or asks for too many
+/// ranges is ignored, as RFC 9110 allows.
+fn parse_ranges(value: &HeaderValue, len: u64) -> Ranges {
+    let Some(specs) = value.to_str().ok().and_then(|v| v.trim().strip_prefix("bytes=")) else {
+        return Ranges::Full
+    };
+
+    let specs: Vec<&str> = specs.split(',').map(str::trim).filter(|spec| !spec.is_empty()).collect();
+
+    if specs.is_empty() || specs.len() > MAX_RANGES {
+        return Ranges::Full
+    }
+
+    let mut ranges = Vec::new();
+
+    for spec in specs {
+        let Some((start, end)) = spec.split_once('-') else {
+            return Ranges::Full
+        };
+
+        let range = match (start.parse::<u64>(), end.parse::<u64>()) {
+            // The last `end` bytes.
+            _ if start.is_empty() => match end.parse::<u64>() {
+                Ok(0) => continue,
+                Ok(suffix) => len.saturating_sub(suffix)..len,
+                Err(_) => return Ranges::Full
+            },
+            (Ok(start), _) if end.is_empty() => start..len,
+            (Ok(start), Ok(end)) if start <= end => start..len.min(end.saturating_add(1)),
+            _ => return Ranges::Full
+        };
+
+        if range.start < range.end {
+            ranges.push(range);
+        }
+    }
+
+    if ranges.is_empty() {
+        Ranges::Unsatisfiable
+    } else {
+        Ranges::Satisfiable(ranges)
+    }
+}
+
+fn content_range(range: Option<&Range<u64>>, len: u64) -> HeaderValue {
+    let value = match range {
+        Some(range) => format!("bytes {}-{}/{len}", range.start, range.end - 1),
+        None => format!("bytes */{len}")
+    };
+
+    HeaderValue::from_str(&value).expect("content range is a valid header value")
+}
+
+/// Reads the segments of a response body one chunk at a time.
+struct SegmentReader {
+    file: File,
+    segments: VecDeque<Segment>,
+    position: u64,
+    buf: Vec<u8>,
+}
+
+impl SegmentReader {
+    async fn next_chunk(&mut self) -> Option<std::io::Result<Vec<u8>>> {
+        let result = self.read_chunk().await;
+
+        if matches!(result, Some(Err(_))) {
+            self.segments.clear();
+        }
+
+        result
+    }
+
+    async fn read_chunk(&mut self) -> Option<std::io::Result<Vec<u8>>> {
+        loop {
+            match self.segments.front_mut()? {
+                Segment::Bytes(bytes) => {
+                    let bytes = std::mem::take(bytes);
+                    self.segments.pop_front();
+
+                    return Some(Ok(bytes))
+                },
+                Segment::File { len: 0, .. } => {
+                    self.segments.pop_front();
+                },
+                Segment::File { start, len } => {
+                    if self.position != *start {
+                        if let Err(e) = self.file.seek(SeekFrom::Start(*start)).await {
+                            return Some(Err(e))
+                        }
+
+                        self.position = *start;
+                    }
+
+                    let want = (*len).min(self.buf.len() as u64) as usize;
+
+                    let read = match self.file.read(&mut self.buf[..want]).await {
+                        Ok(0) => return Some(Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "file shrank while it was sent"))),
+                        Ok(read) => read,
+                        Err(e) => return Some(Err(e))
+                    };
+
+                    *start += read as u64;
+                    *len -= read as u64;
+                    self.position += read as u64;
+
+                    return Some(Ok(self.buf[..read].to_vec()))
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ranges(value: &str, len: u64) -> Option<Vec<(u64, u64)>> {
+        match parse_ranges(&HeaderValue::from_str(value).unwrap(), len) {
+            Ranges::Full => None,
+            Ranges::Satisfiable(ranges) => Some(ranges.into_iter().map(|range| (range.start, range.end)).collect()),
+            Ranges::Unsatisfiable => Some(Vec::new()),
+        }
+    }
+
+    fn request(headers: &[(header::HeaderName, &str)]) -> HeaderMap {
+        headers.iter()
+            .map(|(name, value)| (name.clone(), HeaderValue::from_str(value).unwrap()))
+            .collect()
+    }
+
+    fn date(value: &str) -> DateTime<Utc> {
+        parse_http_date(&HeaderValue::from_str(value).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn single_ranges() {
+        assert_eq!(ranges("bytes=0-99", 1000), Some(vec![(0, 100)]));
+        assert_eq!(ranges("bytes=500-", 1000), Some(vec![(500, 1000)]));
+        assert_eq!(ranges("bytes=-100", 1000), Some(vec![(900, 1000)]));
+        assert_eq!(ranges("bytes=900-5000", 1000), Some(vec![(900, 1000)]));
+        assert_eq!(ranges("bytes=-5000", 1000), Some(vec![(0, 1000)]));
+    }
+
+    #[test]
+    fn multiple_ranges() {
+        assert_eq!(ranges("bytes=0-0, 10-19,-1", 1000), Some(vec![(0, 1), (10, 20), (999, 1000)]));
+        assert_eq!(ranges("bytes=0-0,2000-", 1000), Some(vec![(0, 1)]));
+    }
+
+    #[test]
+    fn unsatisfiable_ranges() {
+        assert_eq!(ranges("bytes=1000-", 1000), Some(Vec::new()));
+        assert_eq!(ranges("bytes=-0", 1000), Some(Vec::new()));
+        assert_eq!(ranges("bytes=0-", 0), Some(Vec::new()));
+    }
+
+    #[test]
+    fn ignored_ranges() {
+        assert_eq!(ranges("items=0-1", 1000), None);
+        assert_eq!(ranges("bytes=", 1000), None);
+        assert_eq!(ranges("bytes=5-1", 1000), None);
+        assert_eq!(ranges("bytes=a-b", 1000), None);
+        assert_eq!(ranges("bytes=10", 1000), None);
+
+        let too_many = vec!["0-0"; MAX_RANGES + 1].join(",");
+        assert_eq!(ranges(&format!("bytes={too_many}"), 1000), None);
+    }
+
+    #[test]
+    fn not_modified_by_etag() {
+        let modified = Some(date("Wed, 21 Oct 2015 07:28:00 GMT"));
+
+        assert!(not_modified(&request(&[(header::IF_NONE_MATCH, "\"abc\"")]), "\"abc\"", modified));
+        assert!(not_modified(&request(&[(header::IF_NONE_MATCH, "\"x\", W/\"abc\"")]), "\"abc\"", modified));
+        assert!(not_modified(&request(&[(header::IF_NONE_MATCH, "*")]), "\"abc\"", modified));
+        assert!(!not_modified(&request(&[(header::IF_NONE_MATCH, "\"def\"")]), "\"abc\"", modified));
+
+        // If-None-Match takes precedence over If-Modified-Since.
+        assert!(!not_modified(&request(&[
+            (header::IF_NONE_MATCH, "\"def\""),
+            (header::IF_MODIFIED_SINCE, "Wed, 21 Oct 2015 07:28:00 GMT"),
+        ]), "\"abc\"", modified));
+    }
+
+    #[test]
+    fn not_modified_by_date() {
+        let modified = Some(date("Wed, 21 Oct 2015 07:28:00 GMT"));
+
+        assert!(not_modified(&request(&[(header::IF_MODIFIED_SINCE, "Wed, 21 Oct 2015 07:28:00 GMT")]), "\"abc\"", modified));
+        assert!(not_modified(&request(&[(header::IF_MODIFIED_SINCE, "Thu, 22 Oct 2015 07:28:00 GMT")]), "\"abc\"", modified));
+        assert!(!not_modified(&request(&[(header::IF_MODIFIED_SINCE, "Tue, 20 Oct 2015 07:28:00 GMT")]), "\"abc\"", modified));
+        assert!(!not_modified(&request(&[(header::IF_MODIFIED_SINCE, "yesterday")]), "\"abc\"", modified));
+        assert!(!not_modified(&request(&[(header::IF_MODIFIED_SINCE, "Wed, 21 Oct 2015 07:28:00 GMT")]), "\"abc\"", None));
+        assert!(!not_modified(&HeaderMap::new(), "\"abc\"", modified));
+    }
+
+    #[test]
+    fn if_range() {
+        let modified = Some(date("Wed, 21 Oct 2015 07:28:00 GMT"));
+
+        assert!(if_range_matches(&HeaderMap::new(), "\"abc\"", modified));
+        assert!(if_range_matches(&request(&[(header::IF_RANGE, "\"abc\"")]), "\"abc\"", modified));
+        assert!(!if_range_matches(&request(&[(header::IF_RANGE, "\"def\"")]), "\"abc\"", modified));
+        assert!(!if_range_matches(&request(&[(header::IF_RANGE, "W/\"abc\"")]), "\"abc\"", modified));
+        assert!(if_range_matches(&request(&[(header::IF_RANGE, "Wed, 21 Oct 2015 07:28:00 GMT")]), "\"abc\"", modified));
+        assert!(!if_range_matches(&request(&[(header::IF_RANGE, "Thu, 22 Oct 2015 07:28:00 GMT")]), "\"abc\"", modified));
+        assert!(!if_range_matches(&request(&[(header::IF_RANGE, "Wed, 21 Oct 2015 07:28:00 GMT")]), "\"abc\"", None));
+    }
+}
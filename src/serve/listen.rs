@@ -0,0 +1,171 @@
+use std::{fmt::Display, net::{Ipv6Addr, SocketAddr}, os::{fd::{FromRawFd, RawFd}, unix::fs::{FileTypeExt, PermissionsExt}}, path::PathBuf, str::FromStr};
+
+use anyhow::{bail, Context};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::{TcpListener, UnixListener};
+
+/// The first file descriptor passed by systemd socket activation.
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// Sockets passed by systemd with this name are served with TLS.
+pub const SD_HTTPS_NAME: &str = "https";
+
+#[derive(Clone, Debug)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    /// IPv6 and IPv4 on one socket.
+    DualStack(u16),
+    Unix(PathBuf),
+}
+
+impl FromStr for ListenAddr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            if path.is_empty() {
+                bail!("expected unix:/path")
+            }
+
+            return Ok(Self::Unix(PathBuf::from(path)))
+        }
+
+        if let Some(port) = s.strip_prefix("*:") {
+            return Ok(Self::DualStack(port.parse().with_context(|| format!("invalid port {port}"))?))
+        }
+
+        Ok(Self::Tcp(s.parse().with_context(|| "expected IP:PORT, [IPv6]:PORT, *:PORT or unix:/path")?))
+    }
+}
+
+impl Display for ListenAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => write!(f, "{addr}"),
+            ListenAddr::DualStack(port) => write!(f, "*:{port}"),
+            ListenAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+pub enum BoundListener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl ListenAddr {
+    /// Binds the address. Unix sockets get `unix_mode` as permissions, replacing a socket left
+    /// behind by an earlier run.
+    pub async fn bind(&self, unix_mode: u32) -> anyhow::Result<BoundListener> {
+        let listener = match self {
+            ListenAddr::Tcp(addr) => BoundListener::Tcp(bind_tcp(*addr, addr.is_ipv6())?),
+            ListenAddr::DualStack(port) => BoundListener::Tcp(bind_tcp(SocketAddr::from((Ipv6Addr::UNSPECIFIED, *port)), false)?),
+            ListenAddr::Unix(path) => {
+                if let Ok(metadata) = tokio::fs::symlink_metadata(path).await && metadata.file_type().is_socket() {
+                    tokio::fs::remove_file(path).await?;
+                }
+
+                let listener = UnixListener::bind(path)?;
+
+                tokio::fs::set_permissions(path, std::fs::Permissions::from_mode(unix_mode)).await?;
+
+                BoundListener::Unix(listener)
+            }
+        };
+
+        Ok(listener)
+    }
+}
+
+fn bind_tcp(addr: SocketAddr, only_v6: bool) -> anyhow::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+
+    if addr.is_ipv6() {
+        socket.set_only_v6(only_v6)?;
+    }
+
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+
+    Ok(TcpListener::from_std(socket.into())?)
+}
+
+/// The listening sockets passed by systemd socket activation, with their `FileDescriptorName`,
+/// or `None` when the process was not started that way.
+pub fn systemd_listeners() -> anyhow::Result<Option<Vec<(String, BoundListener)>>> {
+    let for_us = std::env::var("LISTEN_PID").ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        .is_some_and(|pid| pid == std::process::id());
+
+    let Some(count) = std::env::var("LISTEN_FDS").ok().and_then(|n| n.parse::<RawFd>().ok()) else {
+        return Ok(None)
+    };
+
+    if !for_us || count == 0 {
+        return Ok(None)
+    }
+
+    let names = std::env::var("LISTEN_FDNAMES").unwrap_or_default();
+    let mut names = names.split(':');
+
+    let mut listeners = Vec::new();
+
+    for fd in SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count {
+        let name = names.next().unwrap_or_default().to_string();
+
+        // SAFETY: systemd passes these descriptors to this process, and nothing else owns them.
+        let socket = unsafe { Socket::from_raw_fd(fd) };
+
+        if socket.r#type()? != Type::STREAM {
+            bail!("socket {fd} passed by systemd is not a stream socket")
+        }
+
+        socket.set_nonblocking(true)?;
+
+        let addr = socket.local_addr()?;
+
+        let listener = if addr.is_unix() {
+            BoundListener::Unix(UnixListener::from_std(socket.into())?)
+        } else if addr.as_socket().is_some() {
+            BoundListener::Tcp(TcpListener::from_std(socket.into())?)
+        } else {
+            bail!("socket {fd} passed by systemd is neither TCP nor a unix socket")
+        };
+
+        listeners.push((name, listener));
+    }
+
+    Ok(Some(listeners))
+}
+
+pub fn parse_mode(s: &str) -> anyhow::Result<u32> {
+    u32::from_str_radix(s, 8).with_context(|| format!("expected an octal mode like 660, not {s}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn listen_addr(s: &str) -> String {
+        s.parse::<ListenAddr>().unwrap().to_string()
+    }
+
+    #[test]
+    fn listen_addr_kinds() {
+        assert_eq!(listen_addr("127.0.0.1:8080"), "127.0.0.1:8080");
+        assert_eq!(listen_addr("[::1]:8443"), "[::1]:8443");
+        assert!(matches!("*:80".parse::<ListenAddr>().unwrap(), ListenAddr::DualStack(80)));
+        assert_eq!(listen_addr("unix:/run/zedmirs.sock"), "unix:/run/zedmirs.sock");
+    }
+
+    #[test]
+    fn listen_addr_invalid() {
+        assert!("unix:".parse::<ListenAddr>().is_err());
+        assert!("*:http".parse::<ListenAddr>().is_err());
+        assert!("*:70000".parse::<ListenAddr>().is_err());
+        assert!("localhost:8080".parse::<ListenAddr>().is_err());
+        assert!("8080".parse::<ListenAddr>().is_err());
+    }
+}
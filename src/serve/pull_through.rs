@@ -0,0 +1,455 @@
+use std::{collections::HashMap, path::{Path, PathBuf}, sync::{Arc, Mutex}, time::Duration};
+
+use anyhow::Context;
+use axum::body::Body;
+use clap::Parser;
+use reqwest::{Client, StatusCode};
+use serde_json::Map;
+use tokio::{fs::File, io::{AsyncReadExt, AsyncSeekExt}, sync::{oneshot, watch}};
+
+use crate::{downloader::{create_dirs, link_latest, part_path, Download, Downloader, DownloaderOpts}, ext_searcher::ExtSearcher, index::Indexer, manifest::{ArchiveId, Manifest, ManifestEntry}, package_meta::{cmp_versions, ExtensionListData}, progress::Progress, pulled::{staging_path, PulledArchives}, serve::AppState};
+
+const PULL_THROUGH_THREADS: u8 = 4;
+
+/// How often a client that has caught up with a running download checks for more data.
+const TAIL_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+#[derive(Clone, Parser)]
+pub struct PullThroughOpts {
+    #[arg(long, help="Fetch archives that are not mirrored from the upstream when they are requested")]
+    pub pull_through: bool,
+    #[arg(long, default_value="https://api.zed.dev",
+        help="Zed API url archives are pulled through from")]
+    pub upstream_url: String,
+}
+
+#[derive(Clone, Debug)]
+enum FetchState {
+    Running,
+    Done,
+    Failed(String),
+}
+
+/// What a request waits for before there is a download to follow: the upstream lookup of the
+/// version to fetch.
+#[derive(Clone, Debug)]
+enum Lookup {
+    Pending,
+    Found(ArchiveId, watch::Receiver<FetchState>),
+    Failed(StatusCode),
+}
+
+/// An extension id and the version asked for, `None` for the newest.
+type LookupKey = (String, Option<String>);
+
+/// Fetches archives missing from the mirror on request and keeps them, so that the next request
+/// is served from disk.
+pub struct PullThrough {
+    upstream_url: String,
+    http_client: Client,
+    downloader: Downloader,
+    /// Where the downloader puts the entries of fetched archives until they are recorded.
+    manifest: Manifest,
+    /// Running lookups, so that concurrent requests for the same thing share one.
+    lookups: Mutex<HashMap<LookupKey, watch::Receiver<Lookup>>>,
+    /// Running downloads, so that lookups ending up at the same archive share one.
+    inflight: Mutex<HashMap<ArchiveId, watch::Receiver<FetchState>>>,
+}
+
+impl PullThrough {
+    pub fn new(opts: &PullThroughOpts, downloader_opts: &DownloaderOpts) -> anyhow::Result<Self> {
+        let manifest = Manifest::default();
+
+        Ok(Self {
+            upstream_url: opts.upstream_url.trim_end_matches('/').to_string(),
+            http_client: downloader_opts.build_http_client()?,
+            downloader: Downloader::build(PULL_THROUGH_THREADS, downloader_opts, manifest.clone())?,
+            manifest,
+            lookups: Default::default(),
+            inflight: Default::default(),
+        })
+    }
+
+    /// Starts fetching an archive, or joins the fetch that is already running for it, and returns
+    /// a body that follows the download as it is written. Without a version the newest one
+    /// upstream is fetched and becomes the latest archive.
+    pub async fn fetch(self: &Arc<Self>, state: &AppState, id: &str, requested: Option<&str>) -> Result<Body, StatusCode> {
+        if !is_path_segment(id) || requested.is_some_and(|v| !is_path_segment(v)) {
+            return Err(StatusCode::NOT_FOUND)
+        }
+
+        let key: LookupKey = (id.to_string(), requested.map(str::to_string));
+
+        let mut lookup_rx = {
+            let mut lookups = self.lookups.lock().expect("lookups lock poisoned");
+
+            match lookups.get(&key) {
+                Some(rx) => rx.clone(),
+                None => {
+                    let (tx, rx) = watch::channel(Lookup::Pending);
+                    lookups.insert(key.clone(), rx.clone());
+
+                    // Spawned, so that the fetch goes on for the others when this client goes away.
+                    let pull_through = self.clone();
+                    let state = state.clone();
+
+                    tokio::spawn(async move {
+                        let (id, requested) = &key;
+
+                        _ = tx.send(pull_through.start(&state, id, requested.as_deref()).await);
+
+                        pull_through.lookups.lock().expect("lookups lock poisoned").remove(&key);
+                    });
+
+                    rx
+                }
+            }
+        };
+
+        let lookup = lookup_rx.wait_for(|lookup| !matches!(lookup, Lookup::Pending)).await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+            .clone();
+
+        let (archive, rx) = match lookup {
+            Lookup::Found(archive, rx) => (archive, rx),
+            Lookup::Failed(status) => return Err(status),
+            Lookup::Pending => unreachable!("waited for the lookup to finish")
+        };
+
+        let (staging_path, target_path) = archive_paths(&state.output, &archive);
+
+        let tail = Tail {
+            part_path: part_path(&staging_path),
+            target_path,
+            rx,
+            file: None,
+            sent: 0,
+            buf: vec![0_u8; 64 * 1024],
+            finished: false,
+        };
+
+        let stream = futures_util::stream::unfold(tail, |mut tail| async move {
+            tail.next_chunk().await.map(|chunk| (chunk, tail))
+        });
+
+        Ok(Body::from_stream(stream))
+    }
+
+    /// Looks up the version to fetch upstream and starts downloading it, or joins the download
+    /// that is already running for it.
+    async fn start(self: &Arc<Self>, state: &AppState, id: &str, requested: Option<&str>) -> Lookup {
+        let metadata = match self.lookup(id, requested).await {
+            Ok(metadata) => metadata,
+            Err(status) => return Lookup::Failed(status)
+        };
+
+        let Some(version) = metadata.get("version").and_then(|v| v.as_str()).map(str::to_string) else {
+            return Lookup::Failed(StatusCode::NOT_FOUND)
+        };
+
+        if !is_path_segment(&version) {
+            return Lookup::Failed(StatusCode::NOT_FOUND)
+        }
+
+        let archive = ArchiveId { id: id.to_string(), version };
+        let (staging_path, target_path) = archive_paths(&state.output, &archive);
+
+        let (rx, started) = {
+            let mut inflight = self.inflight.lock().expect("inflight lock poisoned");
+
+            match inflight.get(&archive) {
+                Some(rx) => (rx.clone(), None),
+                // Moved into place by a fetch that finished after the request found nothing on disk.
+                None if target_path.exists() => (watch::channel(FetchState::Done).1, None),
+                None => {
+                    let (tx, rx) = watch::channel(FetchState::Running);
+                    inflight.insert(archive.clone(), rx.clone());
+
+                    (rx, Some(tx))
+                }
+            }
+        };
+
+        let Some(tx) = started else {
+            return Lookup::Found(archive, rx)
+        };
+
+        crate::log(format!("Pulling through {} {}", archive.id, archive.version));
+
+        let (done_tx, done_rx) = oneshot::channel();
+
+        let queued = self.downloader.queue(Box::new(Download {
+            url: format!("{}/extensions/{id}/{}/download", self.upstream_url, archive.version),
+            size: None,
+            primary_target_path: staging_path.to_string_lossy().into_owned(),
+            always_download: false,
+            symlink_path: None,
+            archive: Some(archive.clone()),
+            done: Some(done_tx),
+        })).await;
+
+        if let Err(e) = queued {
+            crate::log(format!("WARN failed to queue {id}: {e}"));
+            self.inflight.lock().expect("inflight lock poisoned").remove(&archive);
+            return Lookup::Failed(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+
+        let pull_through = self.clone();
+        let state = state.clone();
+        let found = archive.clone();
+        let latest_path = requested.is_none().then(|| format!("{}/extensions/{id}/archive.tar.gz", state.output));
+
+        tokio::spawn(async move {
+            let result = match done_rx.await {
+                Ok(Ok(())) => move_into_place(&staging_path, &target_path, latest_path.as_deref()).await
+                    .map_err(|e| format!("{e:#}")),
+                Ok(Err(e)) => Err(e),
+                Err(_) => Err(String::from("download was dropped"))
+            };
+
+            let fetch_state = match result {
+                Ok(()) => {
+                    let entry = pull_through.manifest.get(&archive.id, &archive.version);
+
+                    // The records keep it from now on.
+                    pull_through.manifest.remove(&archive.id, &archive.version);
+
+                    let recorded = match entry {
+                        Some(entry) => record(&state, &archive, metadata, entry).await,
+                        None => Err(anyhow::anyhow!("the download has no manifest entry"))
+                    };
+
+                    if let Err(e) = recorded {
+                        crate::log(format!("WARN failed to index {} {}: {e:#}", archive.id, archive.version));
+                    }
+
+                    FetchState::Done
+                },
+                Err(e) => {
+                    crate::log(format!("WARN failed to pull through {} {}: {e}", archive.id, archive.version));
+                    FetchState::Failed(e)
+                }
+            };
+
+            _ = tx.send(fetch_state);
+
+            pull_through.inflight.lock().expect("inflight lock poisoned").remove(&archive);
+        });
+
+        Lookup::Found(found, rx)
+    }
+
+    /// The upstream metadata of a version, or of the newest version.
+    async fn lookup(&self, id: &str, version: Option<&str>) -> Result<Map<String, serde_json::Value>, StatusCode> {
+        let url = format!("{}/extensions/{id}", self.upstream_url);
+
+        let response = self.http_client.get(&url).send().await
+            .and_then(|r| r.error_for_status());
+
+        let list: ExtensionListData = match response {
+            Ok(response) => response.json().await.map_err(|e| {
+                crate::log(format!("WARN invalid response from {url}: {e}"));
+                StatusCode::BAD_GATEWAY
+            })?,
+            Err(e) if e.status() == Some(StatusCode::NOT_FOUND) => return Err(StatusCode::NOT_FOUND),
+            Err(e) => {
+                crate::log(format!("WARN {url}: {e}"));
+                return Err(StatusCode::BAD_GATEWAY)
+            }
+        };
+
+        let version_of = |doc: &Map<String, serde_json::Value>| doc.get("version")
+            .and_then(|v| v.as_str())
+            .unwrap_or_default()
+            .to_string();
+
+        list.data.into_iter()
+            .filter(|doc| doc.get("id").and_then(|v| v.as_str()) == Some(id))
+            .filter(|doc| version.is_none_or(|version| version_of(doc) == version))
+            .max_by(|a, b| cmp_versions(&version_of(a), &version_of(b)))
+            .ok_or(StatusCode::NOT_FOUND)
+    }
+}
+
+/// Where an archive is downloaded to, and where it is served from once complete.
+fn archive_paths(output: &str, archive: &ArchiveId) -> (PathBuf, PathBuf) {
+    (
+        PathBuf::from(format!("{}/{}/{}/archive.tar.gz", staging_path(output), archive.id, archive.version)),
+        PathBuf::from(format!("{output}/extensions/{}/{}/archive.tar.gz", archive.id, archive.version)),
+    )
+}
+
+/// Moves a downloaded archive from the staging directory to where it is served from, and makes it
+/// the latest archive if `latest_path` is given.
+async fn move_into_place(staging_path: &Path, target_path: &Path, latest_path: Option<&str>) -> anyhow::Result<()> {
+    create_dirs(target_path).await?;
+
+    tokio::fs::rename(staging_path, target_path).await
+        .with_context(|| format!("moving {} into place", staging_path.display()))?;
+
+    // The version and id directories, which only fail to go while other fetches use them.
+    for dir in staging_path.ancestors().skip(1).take(2) {
+        _ = tokio::fs::remove_dir(dir).await;
+    }
+
+    if let Some(latest_path) = latest_path {
+        link_latest(&target_path.to_string_lossy(), latest_path).await?;
+    }
+
+    Ok(())
+}
+
+/// The pulled archives that were recorded when a generation was swapped in, and those pulled
+/// since, searched next to its index. Their index is small, so it is kept in memory and built from
+/// the records.
+pub struct PulledIndex {
+    pub manifest: Manifest,
+    pub searcher: ExtSearcher,
+}
+
+impl PulledIndex {
+    pub async fn load(output: &str) -> anyhow::Result<Self> {
+        let archives = PulledArchives::load(output).await?;
+
+        let indexer = Indexer::in_ram()?;
+
+        let index = tokio::task::spawn_blocking(move || indexer.index(archives.list, Progress::default()).map(|()| indexer.into_index())).await?
+            .with_context(|| "indexing pulled archives")?;
+
+        Ok(Self {
+            manifest: archives.manifest,
+            searcher: ExtSearcher::init(index)?,
+        })
+    }
+
+    async fn add(&self, metadata: Map<String, serde_json::Value>, entry: ManifestEntry) -> anyhow::Result<()> {
+        if self.manifest.get(&entry.id, &entry.version).is_some() {
+            return Ok(())
+        }
+
+        let indexer = Indexer::from_index(self.searcher.index().clone())?;
+        let list = ExtensionListData { data: vec![metadata] };
+
+        tokio::task::spawn_blocking(move || indexer.index(list, Progress::default())).await??;
+
+        self.searcher.reload()?;
+        self.manifest.insert(entry);
+
+        Ok(())
+    }
+}
+
+/// Records a pulled archive and adds it to the snapshot being served. Holds the snapshot lock, so
+/// that a generation swapped in meanwhile does not miss it.
+async fn record(state: &AppState, archive: &ArchiveId, metadata: Map<String, serde_json::Value>, entry: ManifestEntry) -> anyhow::Result<()> {
+    let _swapping = state.swapping.lock().await;
+
+    PulledArchives::record(&state.output, metadata.clone(), entry.clone()).await
+        .with_context(|| format!("recording {} {}", archive.id, archive.version))?;
+
+    state.snapshot().pulled.add(metadata, entry).await
+}
+
+/// Ids and versions end up in paths, so they must not be able to leave their directory.
+fn is_path_segment(s: &str) -> bool {
+    !s.is_empty() && !s.starts_with('.') && !s.contains(['/', '\\'])
+}
+
+/// Follows an archive while it is being downloaded: reads the temporary file as it grows and
+/// finishes once the download is done and everything has been sent.
+struct Tail {
+    target_path: PathBuf,
+    part_path: PathBuf,
+    rx: watch::Receiver<FetchState>,
+    file: Option<File>,
+    sent: u64,
+    buf: Vec<u8>,
+    finished: bool,
+}
+
+impl Tail {
+    async fn next_chunk(&mut self) -> Option<std::io::Result<Vec<u8>>> {
+        if self.finished {
+            return None
+        }
+
+        let result = self.read_chunk().await;
+
+        if !matches!(result, Some(Ok(_))) {
+            self.finished = true;
+        }
+
+        result
+    }
+
+    async fn read_chunk(&mut self) -> Option<std::io::Result<Vec<u8>>> {
+        loop {
+            let state = self.rx.borrow().clone();
+
+            let Some(file) = &mut self.file else {
+                let path = match state {
+                    FetchState::Running => &self.part_path,
+                    FetchState::Done => &self.target_path,
+                    FetchState::Failed(e) => return Some(Err(std::io::Error::other(e)))
+                };
+
+                match File::open(path).await {
+                    Ok(file) => self.file = Some(file),
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound && matches!(state, FetchState::Running) => self.wait().await,
+                    Err(e) => return Some(Err(e))
+                }
+
+                continue
+            };
+
+            let read = match file.read(&mut self.buf).await {
+                Ok(read) => read,
+                Err(e) => return Some(Err(e))
+            };
+
+            if read > 0 {
+                self.sent += read as u64;
+                return Some(Ok(self.buf[..read].to_vec()))
+            }
+
+            match state {
+                FetchState::Running => self.wait().await,
+                FetchState::Failed(e) => return Some(Err(std::io::Error::other(e))),
+                FetchState::Done => {
+                    let size = match tokio::fs::metadata(&self.target_path).await {
+                        Ok(metadata) => metadata.len(),
+                        Err(e) => return Some(Err(e))
+                    };
+
+                    if self.sent == size {
+                        return None
+                    }
+
+                    if self.sent > size {
+                        return Some(Err(std::io::Error::other("archive changed while it was sent")))
+                    }
+
+                    // A failed attempt was discarded and the archive written to a new file, which
+                    // has the same content, so sending continues from there.
+                    let mut file = match File::open(&self.target_path).await {
+                        Ok(file) => file,
+                        Err(e) => return Some(Err(e))
+                    };
+
+                    if let Err(e) = file.seek(std::io::SeekFrom::Start(self.sent)).await {
+                        return Some(Err(e))
+                    }
+
+                    self.file = Some(file);
+                }
+            }
+        }
+    }
+
+    async fn wait(&mut self) {
+        tokio::select! {
+            _ = self.rx.changed() => {},
+            _ = tokio::time::sleep(TAIL_POLL_INTERVAL) => {},
+        }
+    }
+}
@@ -0,0 +1,187 @@
+use std::{fmt::Debug, path::{Path, PathBuf}, sync::{Arc, RwLock}, time::{Duration, SystemTime}};
+
+use anyhow::{bail, Context};
+use axum::serve::Listener;
+use clap::Parser;
+use rustls::{crypto::ring, pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer}, server::{ClientHello, ResolvesServerCert}, sign::CertifiedKey, ServerConfig};
+use tokio::sync::mpsc;
+use tokio_rustls::{server::TlsStream, TlsAcceptor};
+
+use crate::serve::listen::ListenAddr;
+
+/// How often the certificate files are checked for changes.
+const CERT_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Connections that have not completed the handshake by then are dropped.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Parser)]
+pub struct TlsOpts {
+    #[arg(long, requires="tls_key",
+        help="PEM certificate chain to serve HTTPS with, reloaded when it changes")]
+    pub tls_cert: Option<String>,
+    #[arg(long, requires="tls_cert", help="PEM private key of the certificate")]
+    pub tls_key: Option<String>,
+    #[arg(long, default_value_t=8443u16, help="HTTPS port")]
+    pub tls_port: u16,
+    #[arg(long, help="Address to listen on for HTTPS, like --listen. Can be repeated [default: 0.0.0.0:TLS_PORT]")]
+    pub tls_listen: Vec<ListenAddr>,
+    #[arg(long, requires="tls_cert", help="Serve only HTTPS, not plain HTTP")]
+    pub tls_only: bool,
+}
+
+/// Hands out the certificate most recently loaded from disk.
+#[derive(Debug)]
+struct ReloadingCert {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl ReloadingCert {
+    fn load(cert_path: &str, key_path: &str) -> anyhow::Result<Self> {
+        let cert_path = PathBuf::from(cert_path);
+        let key_path = PathBuf::from(key_path);
+
+        let current = load_certified_key(&cert_path, &key_path)?;
+
+        Ok(Self {
+            cert_path,
+            key_path,
+            current: RwLock::new(Arc::new(current)),
+        })
+    }
+
+    fn modified(&self) -> Option<(SystemTime, SystemTime)> {
+        let modified = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+
+        Some((modified(&self.cert_path)?, modified(&self.key_path)?))
+    }
+
+    /// Loads the certificate again whenever one of its files changes. A certificate that fails
+    /// to load is reported and the previous one kept.
+    fn spawn_watcher(self: Arc<Self>) {
+        tokio::spawn(async move {
+            let mut last_modified = self.modified();
+
+            loop {
+                tokio::time::sleep(CERT_CHECK_INTERVAL).await;
+
+                let modified = self.modified();
+
+                if modified.is_none() || modified == last_modified {
+                    continue
+                }
+
+                last_modified = modified;
+
+                match load_certified_key(&self.cert_path, &self.key_path) {
+                    Ok(cert) => {
+                        *self.current.write().expect("certificate lock poisoned") = Arc::new(cert);
+                        crate::log(format!("Reloaded TLS certificate {}", self.cert_path.display()));
+                    },
+                    Err(e) => crate::log(format!("WARN keeping the previous TLS certificate: {e:#}"))
+                }
+            }
+        });
+    }
+}
+
+impl ResolvesServerCert for ReloadingCert {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().expect("certificate lock poisoned").clone())
+    }
+}
+
+fn load_certified_key(cert_path: &Path, key_path: &Path) -> anyhow::Result<CertifiedKey> {
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("reading {}", cert_path.display()))?;
+
+    if certs.is_empty() {
+        bail!("{} contains no certificates", cert_path.display())
+    }
+
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .with_context(|| format!("reading {}", key_path.display()))?;
+
+    CertifiedKey::from_der(certs, key, &ring::default_provider())
+        .with_context(|| format!("{} does not match {}", key_path.display(), cert_path.display()))
+}
+
+/// Builds the TLS configuration, with a certificate that is reloaded when its files change.
+pub fn acceptor(cert_path: &str, key_path: &str) -> anyhow::Result<TlsAcceptor> {
+    let cert = Arc::new(ReloadingCert::load(cert_path, key_path)?);
+
+    cert.clone().spawn_watcher();
+
+    let mut config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_cert_resolver(cert);
+
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Accepts connections and performs the TLS handshakes in the background, so that a slow client
+/// does not hold up the others.
+pub struct TlsListener<L: Listener> {
+    connections: mpsc::Receiver<(TlsStream<L::Io>, L::Addr)>,
+    local_addr: L::Addr,
+}
+
+impl<L> TlsListener<L>
+where
+    L: Listener,
+    L::Addr: Clone + Debug + Sync,
+{
+    pub fn new(mut listener: L, acceptor: TlsAcceptor) -> std::io::Result<Self> {
+        let local_addr = listener.local_addr()?;
+
+        let (sender, connections) = mpsc::channel(64);
+
+        tokio::spawn(async move {
+            while !sender.is_closed() {
+                let (stream, addr) = listener.accept().await;
+
+                let acceptor = acceptor.clone();
+                let sender = sender.clone();
+
+                tokio::spawn(async move {
+                    match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                        Ok(Ok(stream)) => _ = sender.send((stream, addr)).await,
+                        Ok(Err(e)) => crate::log(format!("WARN TLS handshake with {addr:?} failed: {e}")),
+                        Err(_) => crate::log(format!("WARN TLS handshake with {addr:?} timed out"))
+                    }
+                });
+            }
+        });
+
+        Ok(Self {
+            connections,
+            local_addr
+        })
+    }
+}
+
+impl<L> Listener for TlsListener<L>
+where
+    L: Listener,
+    L::Addr: Clone + Debug + Sync,
+{
+    type Io = TlsStream<L::Io>;
+    type Addr = L::Addr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        match self.connections.recv().await {
+            Some(conn) => conn,
+            None => std::future::pending().await
+        }
+    }
+
+    fn local_addr(&self) -> std::io::Result<Self::Addr> {
+        Ok(self.local_addr.clone())
+    }
+}
@@ -0,0 +1,385 @@
+use std::{collections::{BTreeMap, BTreeSet, HashMap}, fmt::Display, path::Path, time::Duration};
+
+use anyhow::{bail, Context};
+use clap::{Parser, ValueEnum};
+use serde::Serialize;
+use tantivy::Index;
+use tokio::{io::AsyncReadExt, sync::oneshot};
+
+use crate::{downloader::{link_latest, Download, Downloader, DownloaderOpts, FileDigest}, ext_searcher::ExtSearcher, generation::current_path, lock::OutputLock, manifest::{ArchiveId, Manifest}, mirror::{id_and_version, latest_of_generation}, progress::spawn_updater, pulled::PulledArchives};
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+#[derive(Clone, Parser)]
+pub struct VerifyOpts {
+    #[arg(long, value_enum, default_value_t=ReportFormat::Text,
+        help="Report format")]
+    pub format: ReportFormat,
+    #[arg(long, help="Re-download broken archives and fix latest symlinks")]
+    pub repair: bool,
+    #[arg(short, long, default_value="https://api.zed.dev",
+        help="Zed API url, used by --repair")]
+    pub api_url: String,
+    #[arg(short, long, default_value_t=8u8)]
+    pub dl_threads: u8,
+    #[command(flatten)]
+    pub downloader: DownloaderOpts,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum ReportFormat {
+    Text,
+    Json,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Problem {
+    MissingArchive { id: String, version: String },
+    UnindexedArchive { id: String, version: String },
+    BadLatestSymlink { id: String, reason: String },
+    EmptyArchive { id: String, version: String },
+    NotGzip { id: String, version: String },
+    HashMismatch { id: String, version: String, expected: String, actual: String },
+    MissingManifestEntry { id: String, version: String },
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Problem::MissingArchive { id, version } =>
+                write!(f, "{id} {version}: indexed but archive is missing"),
+            Problem::UnindexedArchive { id, version } =>
+                write!(f, "{id} {version}: archive has no index entry"),
+            Problem::BadLatestSymlink { id, reason } =>
+                write!(f, "{id}: latest archive symlink {reason}"),
+            Problem::EmptyArchive { id, version } =>
+                write!(f, "{id} {version}: archive is empty"),
+            Problem::NotGzip { id, version } =>
+                write!(f, "{id} {version}: archive is not gzip compressed"),
+            Problem::HashMismatch { id, version, expected, actual } =>
+                write!(f, "{id} {version}: sha256 is {actual}, manifest says {expected}"),
+            Problem::MissingManifestEntry { id, version } =>
+                write!(f, "{id} {version}: archive has no manifest entry"),
+        }
+    }
+}
+
+impl Problem {
+    /// The archive that has to be fetched again to fix the problem, if re-fetching helps.
+    fn archive(&self) -> Option<ArchiveId> {
+        match self {
+            Problem::MissingArchive { id, version } |
+            Problem::EmptyArchive { id, version } |
+            Problem::NotGzip { id, version } |
+            Problem::HashMismatch { id, version, .. } |
+            Problem::MissingManifestEntry { id, version } => Some(ArchiveId { id: id.clone(), version: version.clone() }),
+            Problem::UnindexedArchive { .. } |
+            Problem::BadLatestSymlink { .. } => None
+        }
+    }
+}
+
+#[derive(Default, Serialize)]
+pub struct Report {
+    checked: usize,
+    problems: Vec<Problem>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    repaired: Option<usize>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    repair_failures: Vec<String>,
+}
+
+pub async fn verify(opts: &VerifyOpts, mut output: &str) -> anyhow::Result<()> {
+    if let Some(path) = output.strip_suffix('/') {
+        output = path
+    }
+
+    let generation_path = current_path(output);
+
+    let index = Index::open_in_dir(generation_path.join("idx"))
+        .with_context(|| "opening index")?;
+
+    let searcher = ExtSearcher::init(index)?;
+
+    let manifest_path = generation_path.join("manifest.json");
+
+    let manifest = Manifest::load(&manifest_path).await
+        .with_context(|| "loading manifest")?;
+
+    let mut versions: BTreeMap<String, Vec<String>> = BTreeMap::new();
+
+    for ext in searcher.get_all_extensions().with_context(|| "reading index")? {
+        versions.entry(ext.id).or_default().push(ext.version);
+    }
+
+    let latest = latest_of_generation(&generation_path).await?;
+
+    let pulled = PulledArchives::load(output).await?;
+
+    for doc in &pulled.list.data {
+        let (id, version) = id_and_version(doc)?;
+
+        let known = versions.entry(id.to_string()).or_default();
+
+        if !known.iter().any(|known| known == version) {
+            known.push(version.to_string());
+        }
+    }
+
+    let mut report = Report::default();
+
+    for (id, versions) in &versions {
+        for version in versions {
+            report.checked += 1;
+
+            let recorded_in = if manifest.get(id, version).is_none() { &pulled.manifest } else { &manifest };
+
+            if let Some(problem) = check_archive(output, recorded_in, id, version).await? {
+                report.problems.push(problem);
+            }
+        }
+
+        if let Some(latest) = latest.get(id) && let Some(problem) = check_latest_symlink(output, id, latest).await? {
+            report.problems.push(problem);
+        }
+    }
+
+    for (id, version) in archives_on_disk(output).await? {
+        if !versions.get(&id).is_some_and(|v| v.contains(&version)) {
+            report.problems.push(Problem::UnindexedArchive { id, version });
+        }
+    }
+
+    if opts.repair && !report.problems.is_empty() {
+        let _lock = OutputLock::acquire(output, Duration::ZERO).await?;
+
+        repair(opts, output, &manifest_path, &manifest, &pulled.manifest, &latest, &mut report).await?;
+    }
+
+    match opts.format {
+        ReportFormat::Text => {
+            for problem in &report.problems {
+                println!("{problem}");
+            }
+
+            for failure in &report.repair_failures {
+                println!("repair failed: {failure}");
+            }
+
+            crate::log(format!("Checked {} archives, found {} problems", report.checked, report.problems.len()));
+
+            if let Some(repaired) = report.repaired {
+                crate::log(format!("Repaired {repaired} problems"));
+            }
+        },
+        ReportFormat::Json => println!("{}", serde_json::to_string_pretty(&report)?)
+    }
+
+    let unresolved = report.problems.len() - report.repaired.unwrap_or(0);
+
+    if unresolved > 0 {
+        bail!("{unresolved} problems found")
+    }
+
+    Ok(())
+}
+
+async fn check_archive(output: &str, manifest: &Manifest, id: &str, version: &str) -> anyhow::Result<Option<Problem>> {
+    let path = format!("{output}/extensions/{id}/{version}/archive.tar.gz");
+
+    let (id, version) = (id.to_string(), version.to_string());
+
+    let mut file = match tokio::fs::File::open(&path).await {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Some(Problem::MissingArchive { id, version })),
+        Err(e) => return Err(e).with_context(|| format!("opening {path}"))
+    };
+
+    if file.metadata().await?.len() == 0 {
+        return Ok(Some(Problem::EmptyArchive { id, version }))
+    }
+
+    let mut magic = [0_u8; 2];
+
+    if file.read_exact(&mut magic).await.is_err() || magic != GZIP_MAGIC {
+        return Ok(Some(Problem::NotGzip { id, version }))
+    }
+
+    let Some(entry) = manifest.get(&id, &version) else {
+        return Ok(Some(Problem::MissingManifestEntry { id, version }))
+    };
+
+    let digest = FileDigest::of_file(&path).await
+        .with_context(|| format!("hashing {path}"))?;
+
+    if digest.sha256 != entry.sha256 {
+        return Ok(Some(Problem::HashMismatch { id, version, expected: entry.sha256, actual: digest.sha256 }))
+    }
+
+    Ok(None)
+}
+
+async fn check_latest_symlink(output: &str, id: &str, latest: &str) -> anyhow::Result<Option<Problem>> {
+    let path = format!("{output}/extensions/{id}/archive.tar.gz");
+
+    let problem = |reason: String| Ok(Some(Problem::BadLatestSymlink { id: id.to_string(), reason }));
+
+    let metadata = match tokio::fs::symlink_metadata(&path).await {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return problem("is missing".to_string()),
+        Err(e) => return Err(e).with_context(|| format!("reading {path}"))
+    };
+
+    if !metadata.is_symlink() {
+        return problem("is not a symlink".to_string())
+    }
+
+    let target = tokio::fs::read_link(&path).await?;
+    let expected = Path::new(latest).join("archive.tar.gz");
+
+    if target != expected {
+        return problem(format!("points to {}, expected {}", target.display(), expected.display()))
+    }
+
+    if !tokio::fs::try_exists(&path).await? {
+        return problem(format!("is dangling, {} does not exist", target.display()))
+    }
+
+    Ok(None)
+}
+
+/// All `extensions/{id}/{version}/archive.tar.gz` files on disk.
+async fn archives_on_disk(output: &str) -> anyhow::Result<BTreeSet<(String, String)>> {
+    let mut archives = BTreeSet::new();
+
+    let mut ids = match tokio::fs::read_dir(format!("{output}/extensions")).await {
+        Ok(ids) => ids,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(archives),
+        Err(e) => return Err(e.into())
+    };
+
+    while let Some(id) = ids.next_entry().await? {
+        if !id.file_type().await?.is_dir() {
+            continue
+        }
+
+        let mut versions = tokio::fs::read_dir(id.path()).await?;
+
+        while let Some(version) = versions.next_entry().await? {
+            if version.file_type().await?.is_dir() && tokio::fs::try_exists(version.path().join("archive.tar.gz")).await? {
+                archives.insert((
+                    id.file_name().to_string_lossy().into_owned(),
+                    version.file_name().to_string_lossy().into_owned()
+                ));
+            }
+        }
+    }
+
+    Ok(archives)
+}
+
+async fn repair(opts: &VerifyOpts, output: &str, manifest_path: &Path, manifest: &Manifest, pulled: &Manifest, latest: &HashMap<String, String>, report: &mut Report) -> anyhow::Result<()> {
+    let downloader = Downloader::build(opts.dl_threads, &opts.downloader, manifest.clone())?;
+
+    let progress = downloader.progress();
+
+    progress.set_total_steps(1);
+    progress.next_step("Repairing").await;
+
+    let pb = progress.create_download_progress_bar().await;
+
+    let updater = spawn_updater(vec![(progress.clone(), pb.clone())]).await;
+
+    let mut symlink_fixes = 0;
+    let mut pending = Vec::new();
+
+    for problem in &report.problems {
+        if let Problem::BadLatestSymlink { id, .. } = problem {
+            let primary_path = format!("{output}/extensions/{id}/{}/archive.tar.gz", latest[id]);
+
+            match link_latest(&primary_path, &format!("{output}/extensions/{id}/archive.tar.gz")).await {
+                Ok(()) => symlink_fixes += 1,
+                Err(e) => report.repair_failures.push(format!("{id}: {e:#}"))
+            }
+
+            continue
+        }
+
+        let Some(archive) = problem.archive() else {
+            continue
+        };
+
+        let primary_target_path = format!("{output}/extensions/{}/{}/archive.tar.gz", archive.id, archive.version);
+
+        // A missing manifest entry only needs hashing, which the downloader does for present files.
+        if !matches!(problem, Problem::MissingManifestEntry { .. }) {
+            _ = tokio::fs::remove_file(&primary_target_path).await;
+        }
+
+        // The downloader records whatever upstream serves now, which has to match what was mirrored.
+        let recorded = match problem {
+            Problem::HashMismatch { .. } => manifest.get(&archive.id, &archive.version)
+                .or_else(|| pulled.get(&archive.id, &archive.version)),
+            _ => None
+        };
+
+        let (done_tx, done_rx) = oneshot::channel();
+
+        downloader.queue(Box::new(Download {
+            url: format!("{}/extensions/{}/{}/download", opts.api_url, archive.id, archive.version),
+            size: None,
+            primary_target_path,
+            always_download: false,
+            symlink_path: None,
+            archive: Some(archive.clone()),
+            done: Some(done_tx),
+        })).await?;
+
+        pending.push((archive, recorded, done_rx));
+    }
+
+    progress.wait_for_completion(&pb).await;
+
+    updater.abort();
+
+    let mut archive_fixes = 0;
+
+    for (archive, recorded, done_rx) in pending {
+        match done_rx.await {
+            Ok(Ok(())) => {},
+            Ok(Err(reason)) => {
+                report.repair_failures.push(format!("{} {}: {reason}", archive.id, archive.version));
+                continue
+            },
+            Err(_) => {
+                report.repair_failures.push(format!("{} {}: download was dropped", archive.id, archive.version));
+                continue
+            }
+        }
+
+        if let Some(recorded) = recorded {
+            let fetched = manifest.get(&archive.id, &archive.version);
+
+            if fetched.as_ref().is_none_or(|fetched| fetched.sha256 != recorded.sha256) {
+                let actual = fetched.map(|fetched| fetched.sha256).unwrap_or_default();
+
+                report.repair_failures.push(format!("{} {}: downloaded archive has sha256 {actual}, manifest says {}",
+                    archive.id, archive.version, recorded.sha256));
+
+                manifest.insert(recorded);
+
+                continue
+            }
+        }
+
+        archive_fixes += 1;
+    }
+
+    manifest.save(manifest_path).await
+        .with_context(|| "writing manifest")?;
+
+    report.repaired = Some(symlink_fixes + archive_fixes);
+
+    Ok(())
+}